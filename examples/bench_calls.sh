@@ -0,0 +1,26 @@
+#!/bin/sh
+# Times examples/fib.spl built with each calling convention, prints the best of RUNS runs.
+# Run from the root of the repository: sh examples/bench_calls.sh [RUNS]
+set -e
+
+RUNS=${1:-5}
+cargo build --release -q
+
+for calls in proxy native; do
+    # The build keeps its temporary files next to the path it is given, so it runs in examples/
+    if ! (cd examples && ../target/release/stapel build --calls "$calls" fib.spl > /dev/null); then
+        echo "Could not build examples/fib.spl with --calls $calls, NASM and ld have to be installed"
+        exit 1
+    fi
+    best=
+    for _ in $(seq "$RUNS"); do
+        start=$(date +%s%N)
+        ./examples/fib > /dev/null
+        end=$(date +%s%N)
+        elapsed=$(( (end - start) / 1000000 ))
+        if [ -z "$best" ] || [ "$elapsed" -lt "$best" ]; then
+            best=$elapsed
+        fi
+    done
+    echo "--calls $calls: ${best} ms"
+done
@@ -0,0 +1,987 @@
+//! Linear bytecode of a `Program`, executed by the VM in `vm.rs` and stored in `.splc` files.
+//!
+//! Every instruction is 16 bytes like the op_* encoding of the self-hosted `stapel.spl`: an 8 byte op
+//! code followed by an 8 byte operand. Jumps and calls hold the index of their target instruction.
+//! All integers of a `.splc` file are little-endian:
+//!
+//! ```text
+//! magic       "SPLC"
+//! version     u32, BYTECODE_VERSION
+//! flags       u32, bit 0 is set for checked arithmetic
+//! stack_limit u64, values the data stack can hold
+//! call_limit  u64, deepest nesting of procedure calls
+//! entry       u64, index of the first instruction of main
+//! strings     u64 count, then a u64 length and the bytes of every string
+//! files       u64 count, then a u64 length and the bytes of every source file name
+//! statics     u64 count, then for every memory and data:
+//!                 u8 0, u64 size                 memory
+//!                 u8 1, u64 width, u64 count     data, followed by its values:
+//!                     u8 0, i64 value            integer
+//!                     u8 1, u64 string           string
+//!                 u8 2                           argc
+//!                 u8 3                           argv
+//! procedures  u64 count, then u64 name (string), u64 start, u64 end, u64 file, u64 line, u64 column
+//! locations   u64 count, then u64 instruction, u64 file, u64 line, u64 column
+//! code        u64 count, then u64 op code, i64 operand of every instruction
+//! ```
+
+use std::collections::HashMap;
+
+use crate::compiler::CompilerOptions;
+use crate::expansion::{self, Expansion};
+use crate::interpreter::REGION_LIMIT;
+use crate::operators::InfixOperators;
+use crate::parser::{Block, DataValue, Instruction, InstructionType, PushType};
+use crate::program::Program;
+use crate::throw_exception_span;
+use crate::tokens::Span;
+
+const MAGIC: &[u8; 4] = b"SPLC";
+/// Incremented whenever the format or the meaning of an op changes, older files are rejected
+pub const BYTECODE_VERSION: u32 = 1;
+const FLAG_CHECKED_ARITH: u32 = 1;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Op {
+    /// End of main, exits with 0
+    Halt,
+    Put,
+    Push(i64),
+    Infix(InfixOperators),
+    Pop,
+    Dup,
+    Over,
+    Pick,
+    Swap,
+    Rot,
+    Size,
+    /// Width in bytes
+    Load(usize),
+    Store(usize),
+    /// Start of the called procedure
+    Call(usize),
+    Return,
+    /// Amount of values popped, like `syscall<n>`
+    Syscall(u8),
+    /// Pushes the length and address of a string
+    PushStr(usize),
+    /// Pushes the address of a memory or data
+    PushStatic(usize),
+    Jump(usize),
+    /// Pops a value and jumps when it is 0
+    JumpIfZero(usize),
+    /// Replaces the current call, `call` followed by `return`
+    TailCall(usize),
+    /// Pops the values of a `let`, the first binding takes the deepest value
+    Bind(usize),
+    /// Drops bindings when leaving a `let`
+    Unbind(usize),
+    /// Pushes the value of the binding this many entries below the innermost one
+    Binding(usize),
+    /// Pops the end and start of a for loop
+    For,
+    /// Jumps to the `ForEnd` when the index reached the end
+    ForCheck(usize),
+    /// Increments the index and jumps back to the `ForCheck`
+    ForStep(usize),
+    ForEnd,
+    /// Pushes the index of the for loop this many loops outside of the innermost one
+    Index(usize),
+    Assert,
+    /// Message string
+    Panic(usize),
+}
+
+impl Op {
+    /// The op of an instruction that only works on the data stack, memory and syscalls, which
+    /// `Machine::step` executes for both the interpreter and the VM
+    pub fn primitive(instruction: &InstructionType) -> Option<Op> {
+        let op = match instruction {
+            InstructionType::Put => Op::Put,
+            InstructionType::Push(PushType::Int(i)) => Op::Push(*i),
+            InstructionType::InfixOperators(op) => Op::Infix(op.clone()),
+            InstructionType::Pop => Op::Pop,
+            InstructionType::Dup => Op::Dup,
+            InstructionType::Over => Op::Over,
+            InstructionType::Pick => Op::Pick,
+            InstructionType::Swap => Op::Swap,
+            InstructionType::Rot => Op::Rot,
+            InstructionType::Size => Op::Size,
+            InstructionType::Load(width) => Op::Load(*width),
+            InstructionType::Store(width) => Op::Store(*width),
+            InstructionType::Syscall(count) => Op::Syscall(*count),
+            _ => return None,
+        };
+        Some(op)
+    }
+
+    /// (op code, operand) in a `.splc` file, the op codes of `stapel.spl` are kept
+    fn encode(&self) -> (u64, i64) {
+        match self {
+            Op::Halt => (0, 0),
+            Op::Put => (1, 0),
+            Op::Push(value) => (2, *value),
+            Op::Infix(op) => (3, infix_code(op)),
+            Op::Pop => (6, 0),
+            Op::Dup => (7, 0),
+            Op::Over => (8, 0),
+            Op::Pick => (9, 0),
+            Op::Swap => (10, 0),
+            Op::Rot => (11, 0),
+            Op::Size => (12, 0),
+            Op::Load(width) => (14, *width as i64),
+            Op::Store(width) => (15, *width as i64),
+            Op::Call(target) => (16, *target as i64),
+            Op::Return => (18, 0),
+            Op::Syscall(count) => (19, *count as i64),
+            Op::PushStr(string) => (20, *string as i64),
+            Op::PushStatic(id) => (21, *id as i64),
+            Op::Jump(target) => (22, *target as i64),
+            Op::JumpIfZero(target) => (23, *target as i64),
+            Op::TailCall(target) => (24, *target as i64),
+            Op::Bind(count) => (25, *count as i64),
+            Op::Unbind(count) => (26, *count as i64),
+            Op::Binding(depth) => (27, *depth as i64),
+            Op::For => (28, 0),
+            Op::ForCheck(target) => (29, *target as i64),
+            Op::ForStep(target) => (30, *target as i64),
+            Op::ForEnd => (31, 0),
+            Op::Index(depth) => (32, *depth as i64),
+            Op::Assert => (33, 0),
+            Op::Panic(message) => (34, *message as i64),
+        }
+    }
+
+    fn decode(code: u64, operand: i64) -> Option<Op> {
+        let index = usize::try_from(operand).ok();
+        let op = match code {
+            0 => Op::Halt,
+            1 => Op::Put,
+            2 => Op::Push(operand),
+            3 => Op::Infix(infix_operator(operand)?),
+            6 => Op::Pop,
+            7 => Op::Dup,
+            8 => Op::Over,
+            9 => Op::Pick,
+            10 => Op::Swap,
+            11 => Op::Rot,
+            12 => Op::Size,
+            14 | 15 if ![1, 2, 4, 8].contains(&operand) => return None,
+            14 => Op::Load(index?),
+            15 => Op::Store(index?),
+            16 => Op::Call(index?),
+            18 => Op::Return,
+            19 => Op::Syscall(u8::try_from(operand).ok().filter(|count| *count <= 6)?),
+            20 => Op::PushStr(index?),
+            21 => Op::PushStatic(index?),
+            22 => Op::Jump(index?),
+            23 => Op::JumpIfZero(index?),
+            24 => Op::TailCall(index?),
+            25 => Op::Bind(index?),
+            26 => Op::Unbind(index?),
+            27 => Op::Binding(index?),
+            28 => Op::For,
+            29 => Op::ForCheck(index?),
+            30 => Op::ForStep(index?),
+            31 => Op::ForEnd,
+            32 => Op::Index(index?),
+            33 => Op::Assert,
+            34 => Op::Panic(index?),
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+/// Infix operator codes of `stapel.spl`
+fn infix_code(op: &InfixOperators) -> i64 {
+    match op {
+        InfixOperators::Plus => 1,
+        InfixOperators::Minus => 2,
+        InfixOperators::Multiply => 3,
+        InfixOperators::Divide => 4,
+        InfixOperators::Modulo => 5,
+        InfixOperators::Equals => 6,
+        InfixOperators::NotEquals => 7,
+        InfixOperators::LesserThan => 8,
+        InfixOperators::GreaterThan => 9,
+        InfixOperators::GreaterOrEqualsTo => 10,
+        InfixOperators::LesserOrEqualsTo => 11,
+        InfixOperators::And => 12,
+        InfixOperators::Or => 13,
+    }
+}
+
+fn infix_operator(code: i64) -> Option<InfixOperators> {
+    let op = match code {
+        1 => InfixOperators::Plus,
+        2 => InfixOperators::Minus,
+        3 => InfixOperators::Multiply,
+        4 => InfixOperators::Divide,
+        5 => InfixOperators::Modulo,
+        6 => InfixOperators::Equals,
+        7 => InfixOperators::NotEquals,
+        8 => InfixOperators::LesserThan,
+        9 => InfixOperators::GreaterThan,
+        10 => InfixOperators::GreaterOrEqualsTo,
+        11 => InfixOperators::LesserOrEqualsTo,
+        12 => InfixOperators::And,
+        13 => InfixOperators::Or,
+        _ => return None,
+    };
+    Some(op)
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Static {
+    /// Size in bytes
+    Memory(usize),
+    /// The memories `argc` and `argv`, which hold the arguments of the program
+    Argc,
+    Argv,
+    Data { width: usize, values: Vec<StaticValue> },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StaticValue {
+    Int(i64),
+    /// Index of the string
+    Str(usize),
+}
+
+/// Source location, `file` is an index into the file names
+#[derive(Debug, PartialEq, Clone)]
+pub struct Location {
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ProcedureInfo {
+    /// Index of the string holding the name
+    pub identifier: usize,
+    /// First instruction, and the one after the last
+    pub start: usize,
+    pub end: usize,
+    pub location: Location,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Bytecode {
+    pub checked_arith: bool,
+    pub stack_limit: usize,
+    pub call_limit: usize,
+    /// First instruction of main
+    pub entry: usize,
+    pub strings: Vec<String>,
+    pub files: Vec<String>,
+    pub statics: Vec<Static>,
+    /// Sorted by start
+    pub procedures: Vec<ProcedureInfo>,
+    /// (first instruction, location) sorted by instruction, every instruction has the location of
+    /// the last entry at or before it
+    pub locations: Vec<(usize, Location)>,
+    pub code: Vec<Op>,
+}
+
+impl Bytecode {
+    /// The options the program was built with, as far as they matter to the VM
+    pub fn options(&self) -> CompilerOptions {
+        CompilerOptions { checked_arith: self.checked_arith, stack_limit: Some(self.stack_limit), ret_stack_size: Some(self.call_limit), ..Default::default() }
+    }
+
+    /// Location of the instruction
+    pub fn location(&self, pc: usize) -> Span {
+        let index = self.locations.partition_point(|(start, _)| *start <= pc);
+        match index.checked_sub(1).map(|index| &self.locations[index].1) {
+            Some(location) => self.span(location),
+            None => Span::new(String::from("<unknown>"), 0, 0),
+        }
+    }
+
+    /// The procedure containing the instruction
+    pub fn procedure(&self, pc: usize) -> Option<&ProcedureInfo> {
+        self.procedures.iter().find(|procedure| (procedure.start..procedure.end).contains(&pc))
+    }
+
+    pub fn span(&self, location: &Location) -> Span {
+        Span::new(self.files[location.file].clone(), location.line, location.column)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut w = Writer::default();
+        w.bytes.extend(MAGIC);
+        w.u32(BYTECODE_VERSION);
+        w.u32(if self.checked_arith { FLAG_CHECKED_ARITH } else { 0 });
+        w.u64(self.stack_limit);
+        w.u64(self.call_limit);
+        w.u64(self.entry);
+
+        w.u64(self.strings.len());
+        for string in &self.strings {
+            w.str(string);
+        }
+        w.u64(self.files.len());
+        for file in &self.files {
+            w.str(file);
+        }
+
+        w.u64(self.statics.len());
+        for stat in &self.statics {
+            match stat {
+                Static::Memory(size) => {
+                    w.bytes.push(0);
+                    w.u64(*size);
+                }
+                Static::Argc => w.bytes.push(2),
+                Static::Argv => w.bytes.push(3),
+                Static::Data { width, values } => {
+                    w.bytes.push(1);
+                    w.u64(*width);
+                    w.u64(values.len());
+                    for value in values {
+                        match value {
+                            StaticValue::Int(i) => {
+                                w.bytes.push(0);
+                                w.i64(*i);
+                            }
+                            StaticValue::Str(string) => {
+                                w.bytes.push(1);
+                                w.u64(*string);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        w.u64(self.procedures.len());
+        for procedure in &self.procedures {
+            w.u64(procedure.identifier);
+            w.u64(procedure.start);
+            w.u64(procedure.end);
+            w.location(&procedure.location);
+        }
+        w.u64(self.locations.len());
+        for (pc, location) in &self.locations {
+            w.u64(*pc);
+            w.location(location);
+        }
+
+        w.u64(self.code.len());
+        for op in &self.code {
+            let (code, operand) = op.encode();
+            w.bytes.extend(code.to_le_bytes());
+            w.i64(operand);
+        }
+        w.bytes
+    }
+
+    /// Reads a `.splc` file, checking every index so the VM can use them without bounds checks failing,
+    /// and the widths and sizes of the data and memories before anything is allocated
+    pub fn from_bytes(bytes: &[u8]) -> Result<Bytecode, String> {
+        let mut r = Reader { bytes, position: 0 };
+        if r.take(4)? != MAGIC {
+            return Err(String::from("not a Stapel bytecode file"));
+        }
+        let version = r.u32()?;
+        if version != BYTECODE_VERSION {
+            return Err(format!("built for bytecode version {}, this stapel runs version {}, rebuild it with 'stapel build --bytecode'", version, BYTECODE_VERSION));
+        }
+        let mut bytecode = Bytecode {
+            checked_arith: r.u32()? & FLAG_CHECKED_ARITH != 0,
+            stack_limit: r.u64()?,
+            call_limit: r.u64()?,
+            entry: r.u64()?,
+            ..Default::default()
+        };
+
+        for _ in 0..r.u64()? {
+            bytecode.strings.push(r.str()?);
+        }
+        for _ in 0..r.u64()? {
+            bytecode.files.push(r.str()?);
+        }
+        let strings = bytecode.strings.len();
+
+        for _ in 0..r.u64()? {
+            let stat = match r.u8()? {
+                0 => match r.u64()? {
+                    size if size > REGION_LIMIT => return Err(format!("a memory of {} bytes does not fit into the static memory", size)),
+                    size => Static::Memory(size),
+                },
+                1 => {
+                    let width = r.u64()?;
+                    if ![1, 2, 4, 8].contains(&width) {
+                        return Err(format!("invalid data width {}", width));
+                    }
+                    let mut values = Vec::new();
+                    for _ in 0..r.u64()? {
+                        values.push(match r.u8()? {
+                            0 => StaticValue::Int(r.i64()?),
+                            1 => StaticValue::Str(r.index(strings)?),
+                            _ => return Err(String::from("invalid data value")),
+                        });
+                    }
+                    Static::Data { width, values }
+                }
+                2 => Static::Argc,
+                3 => Static::Argv,
+                _ => return Err(String::from("invalid static")),
+            };
+            bytecode.statics.push(stat);
+        }
+
+        for _ in 0..r.u64()? {
+            let procedure = ProcedureInfo { identifier: r.index(strings)?, start: r.u64()?, end: r.u64()?, location: r.location(bytecode.files.len())? };
+            bytecode.procedures.push(procedure);
+        }
+        for _ in 0..r.u64()? {
+            let pc = r.u64()?;
+            bytecode.locations.push((pc, r.location(bytecode.files.len())?));
+        }
+
+        for _ in 0..r.u64()? {
+            let code = u64::from_le_bytes(r.take(8)?.try_into().unwrap());
+            let operand = r.i64()?;
+            let Some(op) = Op::decode(code, operand) else {
+                return Err(format!("invalid instruction {} {} at {}", code, operand, bytecode.code.len()));
+            };
+            bytecode.code.push(op);
+        }
+        if r.position != bytes.len() {
+            return Err(String::from("unexpected bytes after the code"));
+        }
+
+        bytecode.check()?;
+        Ok(bytecode)
+    }
+
+    /// Checks that the targets of the instructions exist
+    fn check(&self) -> Result<(), String> {
+        let length = self.code.len();
+        if self.entry >= length {
+            return Err(String::from("the entry point is outside of the code"));
+        }
+        if !matches!(self.code.last(), Some(Op::Halt | Op::Return | Op::Jump(_) | Op::TailCall(_))) {
+            return Err(String::from("the code does not end with a jump"));
+        }
+        for (pc, op) in self.code.iter().enumerate() {
+            let valid = match op {
+                Op::Call(target) | Op::TailCall(target) | Op::Jump(target) | Op::JumpIfZero(target) | Op::ForCheck(target) | Op::ForStep(target) => *target < length,
+                Op::PushStr(string) | Op::Panic(string) => *string < self.strings.len(),
+                Op::PushStatic(id) => *id < self.statics.len(),
+                _ => true,
+            };
+            if !valid {
+                return Err(format!("instruction {} refers to something that does not exist", pc));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u32(&mut self, value: u32) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: usize) {
+        self.bytes.extend((value as u64).to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.bytes.extend(value.to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u64(value.len());
+        self.bytes.extend(value.as_bytes());
+    }
+
+    fn location(&mut self, location: &Location) {
+        self.u64(location.file);
+        self.u64(location.line);
+        self.u64(location.column);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], String> {
+        let Some(bytes) = self.bytes.get(self.position..self.position.saturating_add(length)) else {
+            return Err(String::from("the file ends unexpectedly"));
+        };
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Result<usize, String> {
+        let value = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        usize::try_from(value).map_err(|_| String::from("value out of range"))
+    }
+
+    fn i64(&mut self) -> Result<i64, String> {
+        Ok(i64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// An index into a table of `length` entries
+    fn index(&mut self, length: usize) -> Result<usize, String> {
+        let index = self.u64()?;
+        if index >= length {
+            return Err(format!("index {} is out of bounds", index));
+        }
+        Ok(index)
+    }
+
+    fn str(&mut self) -> Result<String, String> {
+        let length = self.u64()?;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(|_| String::from("invalid string"))
+    }
+
+    fn location(&mut self, files: usize) -> Result<Location, String> {
+        Ok(Location { file: self.index(files)?, line: self.u64()?, column: self.u64()? })
+    }
+}
+
+/// Lowers the program into bytecode, exits with an error at constructs only the NASM backend supports
+pub fn lower(program: &Program, options: &CompilerOptions) -> Bytecode {
+    let mut lowering = Lowering {
+        program,
+        options,
+        bytecode: Bytecode {
+            checked_arith: options.checked_arith,
+            stack_limit: options.data_stack_limit(),
+            call_limit: options.call_limit(),
+            ..Default::default()
+        },
+        strings: HashMap::new(),
+        files: HashMap::new(),
+        statics: HashMap::new(),
+        calls: Vec::new(),
+        loop_stack: Vec::new(),
+        bindings: Vec::new(),
+        expansion: Expansion::new(),
+    };
+
+    // main first, the rest sorted so the same program always gives the same file
+    let mut identifiers: Vec<&String> = program.procedures.keys().filter(|identifier| *identifier != "main").collect();
+    identifiers.sort();
+    let mut starts: HashMap<&str, usize> = HashMap::new();
+    for identifier in std::iter::once(&String::from("main")).chain(identifiers) {
+        let procedure = &program.procedures[identifier.as_str()];
+        let start = lowering.bytecode.code.len();
+        starts.insert(&procedure.identifier, start);
+
+        lowering.lower_block(&procedure.block);
+        lowering.emit(if identifier == "main" { Op::Halt } else { Op::Return });
+
+        let identifier = lowering.string(&procedure.identifier);
+        let location = lowering.location(&procedure.span);
+        let end = lowering.bytecode.code.len();
+        lowering.bytecode.procedures.push(ProcedureInfo { identifier, start, end, location });
+    }
+
+    for (pc, identifier) in std::mem::take(&mut lowering.calls) {
+        let target = starts[identifier.as_str()];
+        match &mut lowering.bytecode.code[pc] {
+            Op::Call(start) | Op::TailCall(start) => *start = target,
+            _ => unreachable!("Only calls are patched with the start of a procedure"),
+        }
+    }
+    lowering.bytecode
+}
+
+struct Loop {
+    /// Bindings in scope at the start of the loop, which break and continue keep
+    bindings: usize,
+    breaks: Vec<usize>,
+    continues: Vec<usize>,
+}
+
+struct Lowering<'a> {
+    program: &'a Program,
+    options: &'a CompilerOptions,
+    bytecode: Bytecode,
+    strings: HashMap<String, usize>,
+    files: HashMap<String, usize>,
+    statics: HashMap<String, usize>,
+    /// (instruction, procedure) of the calls, patched once every procedure has its start
+    calls: Vec<(usize, String)>,
+    loop_stack: Vec<Loop>,
+    /// Ids of the bindings in scope, the innermost last
+    bindings: Vec<usize>,
+    expansion: Expansion<&'a [Block]>,
+}
+
+impl<'a> Lowering<'a> {
+    fn emit(&mut self, op: Op) -> usize {
+        self.bytecode.code.push(op);
+        self.bytecode.code.len() - 1
+    }
+
+    fn pc(&self) -> usize {
+        self.bytecode.code.len()
+    }
+
+    /// Points the jump at `pc` to `target`
+    fn patch(&mut self, pc: usize, target: usize) {
+        match &mut self.bytecode.code[pc] {
+            Op::Jump(to) | Op::JumpIfZero(to) | Op::ForCheck(to) => *to = target,
+            _ => unreachable!("Only jumps are patched"),
+        }
+    }
+
+    fn string(&mut self, str: &str) -> usize {
+        if let Some(id) = self.strings.get(str) {
+            return *id;
+        }
+        self.bytecode.strings.push(str.to_string());
+        self.strings.insert(str.to_string(), self.bytecode.strings.len() - 1);
+        self.bytecode.strings.len() - 1
+    }
+
+    fn location(&mut self, span: &Span) -> Location {
+        let file = match self.files.get(&span.file) {
+            Some(file) => *file,
+            None => {
+                self.bytecode.files.push(span.file.clone());
+                self.files.insert(span.file.clone(), self.bytecode.files.len() - 1);
+                self.bytecode.files.len() - 1
+            }
+        };
+        Location { file, line: span.line, column: span.column }
+    }
+
+    /// Index of a memory or data, added when it is first used
+    fn static_id(&mut self, identifier: &String) -> usize {
+        if let Some(id) = self.statics.get(identifier) {
+            return *id;
+        }
+
+        let stat = match (self.program.memories.get(identifier), self.program.datas.get(identifier)) {
+            (Some(_), _) if identifier == "argc" => Static::Argc,
+            (Some(_), _) if identifier == "argv" => Static::Argv,
+            (Some(memory), _) => Static::Memory(memory.size),
+            (_, Some(data)) => {
+                let values = data.values.iter().map(|value| match value {
+                    DataValue::Int(i) => StaticValue::Int(*i),
+                    DataValue::Str(str, _) => StaticValue::Str(self.string(str)),
+                });
+                Static::Data { width: data.width, values: values.collect() }
+            }
+            _ => unreachable!("Only memories and data have an address"),
+        };
+        self.bytecode.statics.push(stat);
+        self.statics.insert(identifier.clone(), self.bytecode.statics.len() - 1);
+        self.bytecode.statics.len() - 1
+    }
+
+    fn lower_block(&mut self, block: &'a Block) {
+        for (index, instruction) in block.instructions.iter().enumerate() {
+            let tail = !self.options.no_tco && block.instructions.get(index + 1).is_some_and(|next| next.instruction_type == InstructionType::Return);
+            self.lower_instruction(instruction, tail);
+        }
+    }
+
+    /// Lowers one instruction, `tail` when it is followed by a `return`
+    fn lower_instruction(&mut self, instruction: &'a Instruction, tail: bool) {
+        let span = &instruction.span;
+        let location = self.location(span);
+        let pc = self.pc();
+        match self.bytecode.locations.last_mut() {
+            // Instructions that emit nothing leave their location to the next one
+            Some((start, last)) if *start == pc => *last = location,
+            Some((_, last)) if *last == location => (),
+            _ => self.bytecode.locations.push((pc, location)),
+        }
+
+        if let Some(op) = Op::primitive(&instruction.instruction_type) {
+            self.emit(op);
+            return;
+        }
+        match &instruction.instruction_type {
+            InstructionType::Push(PushType::Str(str, _)) => {
+                let string = self.string(str);
+                self.emit(Op::PushStr(string));
+            }
+            InstructionType::While(whl) => {
+                let start = self.pc();
+                self.lower_block(&whl.condition);
+                let exit = self.emit(Op::JumpIfZero(0));
+
+                self.lower_loop(&whl.block);
+                self.emit(Op::Jump(start));
+
+                let lp = self.loop_stack.pop().unwrap();
+                let end = self.pc();
+                for jump in lp.breaks.into_iter().chain([exit]) {
+                    self.patch(jump, end);
+                }
+                for jump in lp.continues {
+                    self.patch(jump, start);
+                }
+            }
+            InstructionType::For(fr) => {
+                self.lower_block(&fr.range);
+                self.emit(Op::For);
+                let check = self.emit(Op::ForCheck(0));
+
+                self.expansion.enter_loop();
+                self.lower_loop(&fr.block);
+                self.expansion.leave_loop();
+                let step = self.emit(Op::ForStep(check));
+
+                let lp = self.loop_stack.pop().unwrap();
+                let end = self.emit(Op::ForEnd);
+                for jump in lp.breaks.into_iter().chain([check]) {
+                    self.patch(jump, end);
+                }
+                for jump in lp.continues {
+                    self.patch(jump, step);
+                }
+            }
+            InstructionType::Index => {
+                let Some(depth) = self.expansion.index_depth() else {
+                    throw_exception_span(span, "'i' can only be used inside of a for loop".to_string());
+                    unreachable!();
+                };
+                self.emit(Op::Index(depth));
+            }
+            InstructionType::Let(lt) => {
+                let count = lt.bindings.len();
+                self.emit(Op::Bind(count));
+                self.bindings.extend(lt.bindings.iter().map(|binding| binding.id));
+                self.lower_block(&lt.block);
+                self.bindings.truncate(self.bindings.len() - count);
+                self.emit(Op::Unbind(count));
+            }
+            InstructionType::Binding(binding) => {
+                let Some(position) = self.bindings.iter().rposition(|id| *id == binding.id) else {
+                    throw_exception_span(span, format!("'{}' is not bound here", binding.identifier));
+                    unreachable!();
+                };
+                self.emit(Op::Binding(self.bindings.len() - 1 - position));
+            }
+            InstructionType::If(iff) => {
+                let mut ends = Vec::new();
+                for (condition, body) in std::iter::once(&iff.if_block).chain(&iff.elif_blocks) {
+                    self.lower_block(condition);
+                    let next = self.emit(Op::JumpIfZero(0));
+                    self.lower_block(body);
+                    ends.push(self.emit(Op::Jump(0)));
+                    self.patch(next, self.pc());
+                }
+                if let Some(else_block) = &iff.else_block {
+                    self.lower_block(else_block);
+                }
+                for jump in ends {
+                    self.patch(jump, self.pc());
+                }
+            }
+            InstructionType::Match(mtch) => {
+                // The value stays on the stack while it is compared with the cases
+                self.lower_block(&mtch.value);
+                let mut ends = Vec::new();
+                for (case, body) in &mtch.cases {
+                    self.emit(Op::Dup);
+                    self.emit(Op::Push(*case));
+                    self.emit(Op::Infix(InfixOperators::Equals));
+                    let next = self.emit(Op::JumpIfZero(0));
+                    self.emit(Op::Pop);
+                    self.lower_block(body);
+                    ends.push(self.emit(Op::Jump(0)));
+                    self.patch(next, self.pc());
+                }
+                self.emit(Op::Pop);
+                if let Some(else_block) = &mtch.else_block {
+                    self.lower_block(else_block);
+                }
+                for jump in ends {
+                    self.patch(jump, self.pc());
+                }
+            }
+            InstructionType::MacroCall(call) => {
+                let inline = expansion::called_inline(self.program, call, span);
+                let block = self.expansion.enter(inline, &call.arguments, span);
+                self.lower_block(block);
+                self.expansion.leave();
+            }
+            InstructionType::Argument(_, index) => {
+                let scope = self.expansion.enter_argument();
+                self.lower_block(&scope.arguments[*index]);
+                self.expansion.leave_argument(scope);
+            }
+            InstructionType::Assert => {
+                // The condition is still consumed in release builds
+                self.emit(if self.options.release { Op::Pop } else { Op::Assert });
+            }
+            InstructionType::Panic(message) => {
+                if !self.options.release {
+                    let message = self.string(message);
+                    self.emit(Op::Panic(message));
+                }
+            }
+            InstructionType::Asm(_) => {
+                throw_exception_span(span, "asm blocks can only be compiled with the NASM backend, they can not be lowered to bytecode".to_string());
+            }
+            InstructionType::Identifier(identifier) => {
+                if self.program.procedures.contains_key(identifier) {
+                    let call = self.emit(if tail { Op::TailCall(0) } else { Op::Call(0) });
+                    self.calls.push((call, identifier.clone()));
+                } else if let Some(inline) = self.program.inlines.get(identifier) {
+                    let block = self.expansion.enter_plain(inline, span);
+                    self.lower_block(block);
+                    self.expansion.leave();
+                } else if self.program.memories.contains_key(identifier) || self.program.datas.contains_key(identifier) {
+                    let id = self.static_id(identifier);
+                    self.emit(Op::PushStatic(id));
+                } else if self.program.externs.contains_key(identifier) {
+                    throw_exception_span(span, format!("'{}' is a C function, extern procedures can not be lowered to bytecode", identifier));
+                } else {
+                    throw_exception_span(span, format!("Word '{}' is not known", identifier));
+                }
+            }
+            InstructionType::Return => _ = self.emit(Op::Return),
+            InstructionType::Break | InstructionType::Continue => {
+                let is_break = instruction.instruction_type == InstructionType::Break;
+                let Some(lp) = self.loop_stack.last() else {
+                    throw_exception_span(span, format!("'{}' can only be used inside of a loop", if is_break { "break" } else { "continue" }));
+                    unreachable!();
+                };
+                let unbind = self.bindings.len() - lp.bindings;
+                if unbind > 0 {
+                    self.emit(Op::Unbind(unbind));
+                }
+                let jump = self.emit(Op::Jump(0));
+                let lp = self.loop_stack.last_mut().unwrap();
+                if is_break {
+                    lp.breaks.push(jump);
+                } else {
+                    lp.continues.push(jump);
+                }
+            }
+            _ => unreachable!("Primitives are lowered by Op::primitive"),
+        }
+    }
+
+    /// Lowers the body of a loop, leaving the loop on the loop stack for its jumps to be patched
+    fn lower_loop(&mut self, block: &'a Block) {
+        self.loop_stack.push(Loop { bindings: self.bindings.len(), breaks: Vec::new(), continues: Vec::new() });
+        self.lower_block(block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::preprocessor;
+
+    const SOURCE: &str = "
+        memory buffer 16 end
+        data greeting 1 \"Hello\" 10 end
+        data names 8 \"put\" \"push\" end
+
+        proc print do 1 1 syscall4 pop end
+
+        proc count do
+            if dup 0 = do return end
+            1 - count return
+        end
+
+        proc main do
+            \"Hi\\n\" print
+            buffer 7 @8 buffer !8 put
+            greeting names + put
+            3 count pop
+            for 0 4 do
+                i let x in
+                    x match
+                        case 1 do continue
+                        case 3 do break
+                        else x put
+                    end
+                end
+            end
+            0 while dup 3 < do 1 + end put
+            1 assert
+            if 0 do panic \"unreachable\" end
+        end";
+
+    fn lower_source(source: &str) -> Bytecode {
+        crate::recover_from_errors();
+        let options = CompilerOptions::default();
+        let mut l = Lexer::new(source.to_string(), String::from("test.spl"));
+        l.tokenize();
+        let mut p = Parser::new(preprocessor::process(l.tokens, &preprocessor::defines(&options)));
+        p.parse();
+        lower(&p.program, &options)
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytecode = lower_source(SOURCE);
+        assert_eq!(Bytecode::from_bytes(&bytecode.to_bytes()), Ok(bytecode));
+    }
+
+    #[test]
+    fn syscalls_round_trip() {
+        let bytecode = lower_source("proc main do syscall0 syscall1 syscall6 end");
+        assert_eq!(bytecode.code.iter().filter(|op| matches!(op, Op::Syscall(_))).count(), 3);
+        assert_eq!(Bytecode::from_bytes(&bytecode.to_bytes()), Ok(bytecode));
+    }
+
+    #[test]
+    fn rejects_malformed_files() {
+        let bytecode = lower_source(SOURCE);
+        let bytes = bytecode.to_bytes();
+        let rejects = |bytes: &[u8]| assert!(Bytecode::from_bytes(bytes).is_err());
+
+        let mut magic = bytes.clone();
+        magic[0] = b'X';
+        rejects(&magic);
+
+        let mut version = bytes.clone();
+        version[4] = version[4].wrapping_add(1);
+        rejects(&version);
+
+        rejects(&bytes[..bytes.len() - 1]);
+        rejects(&[bytes.as_slice(), &[0]].concat());
+
+        let mut jump = bytecode.clone();
+        jump.code[0] = Op::Jump(jump.code.len());
+        rejects(&jump.to_bytes());
+
+        let mut entry = bytecode.clone();
+        entry.entry = entry.code.len();
+        rejects(&entry.to_bytes());
+
+        let mut width = bytecode.clone();
+        width.statics.push(Static::Data { width: 16, values: vec![StaticValue::Int(1)] });
+        assert_eq!(Bytecode::from_bytes(&width.to_bytes()), Err(String::from("invalid data width 16")));
+
+        let mut memory = bytecode.clone();
+        memory.statics.push(Static::Memory(REGION_LIMIT + 1));
+        assert!(Bytecode::from_bytes(&memory.to_bytes()).is_err_and(|message| message.contains("does not fit")));
+    }
+}
@@ -0,0 +1,249 @@
+use crate::compiler::CompilerOptions;
+use crate::expansion::{self, Expansion};
+use crate::parser::{Block, Instruction, InstructionType, Procedure};
+use crate::program::Program;
+use crate::throw_exception_span;
+use crate::tokens::Span;
+
+/// Stack depth relative to the start of the procedure being checked
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Depth {
+    Known(i64),
+    /// Depends on something the checker can not follow, e.g. a procedure call
+    Unknown,
+    /// Control flow does not continue past this point (break, continue, return)
+    Diverged,
+}
+
+impl Depth {
+    fn apply(self, effect: i64) -> Depth {
+        match self {
+            Depth::Known(depth) => Depth::Known(depth + effect),
+            other => other,
+        }
+    }
+
+    /// Depth where two branches of control flow join
+    fn merge(self, other: Depth) -> Depth {
+        match (self, other) {
+            (Depth::Diverged, depth) | (depth, Depth::Diverged) => depth,
+            (Depth::Known(a), Depth::Known(b)) if a == b => Depth::Known(a),
+            _ => Depth::Unknown,
+        }
+    }
+}
+
+/// Depths a loop expects when it is left through `break` or re-entered through `continue`
+struct Loop<'a> {
+    break_depth: Depth,
+    continue_depth: Depth,
+    /// (instruction, depth) of the `break`s and `continue`s of the body, verified once the effect
+    /// of the body is known
+    exits: Vec<(&'a Instruction, Depth)>,
+}
+
+/// Verifies the stack depth at the exit points of loops and of exported procedures, as far as it can
+/// be derived statically. Procedure calls have an unknown stack effect, past those nothing is checked.
+pub fn check(program: &Program, options: &CompilerOptions) {
+    let mut checker = StackChecker { program, options, loops: Vec::new(), expansion: Expansion::new(), exported: None };
+    for procedure in program.procedures.values() {
+        match &procedure.export {
+            // The wrapper pushes the arguments and takes the result from the top of the stack
+            Some(effect) => {
+                checker.exported = Some((procedure, effect.outputs.len() as i64));
+                let depth = checker.check_block(&procedure.block, Depth::Known(effect.inputs.len() as i64));
+                checker.check_return(&procedure.span, depth);
+                checker.exported = None;
+            }
+            None => _ = checker.check_block(&procedure.block, Depth::Known(0)),
+        }
+    }
+}
+
+struct StackChecker<'a> {
+    program: &'a Program,
+    options: &'a CompilerOptions,
+    loops: Vec<Loop<'a>>,
+    expansion: Expansion<&'a [Block]>,
+    /// (procedure, amount of results) of the exported procedure being checked
+    exported: Option<(&'a Procedure, i64)>,
+}
+
+impl<'a> StackChecker<'a> {
+    fn check_block(&mut self, block: &'a Block, mut depth: Depth) -> Depth {
+        for instruction in &block.instructions {
+            depth = self.check_instruction(instruction, depth);
+        }
+        depth
+    }
+
+    fn check_instruction(&mut self, instruction: &'a Instruction, depth: Depth) -> Depth {
+        if depth == Depth::Diverged {
+            return depth; // Unreachable code
+        }
+
+        match &instruction.instruction_type {
+            InstructionType::While(whl) => {
+                let condition = self.check_block(&whl.condition, depth).apply(-1);
+                self.check_loop(&whl.block, depth, condition, depth)
+            }
+            InstructionType::For(fr) => {
+                let range = self.check_block(&fr.range, depth).apply(-2);
+                self.check_loop(&fr.block, range, range, range)
+            }
+            InstructionType::If(iff) => {
+                let mut condition = self.check_block(&iff.if_block.0, depth).apply(-1);
+                let mut result = self.check_block(&iff.if_block.1, condition);
+
+                for (cond, body) in &iff.elif_blocks {
+                    condition = self.check_block(cond, condition).apply(-1);
+                    result = result.merge(self.check_block(body, condition));
+                }
+
+                match &iff.else_block {
+                    Some(else_block) => result.merge(self.check_block(else_block, condition)),
+                    None => result.merge(condition),
+                }
+            }
+            InstructionType::Match(mtch) => {
+                let value = self.check_block(&mtch.value, depth).apply(-1);
+                let mut result = match &mtch.else_block {
+                    Some(else_block) => self.check_block(else_block, value),
+                    None => value,
+                };
+                for (_, block) in &mtch.cases {
+                    result = result.merge(self.check_block(block, value));
+                }
+                result
+            }
+            InstructionType::Let(lt) => self.check_block(&lt.block, depth.apply(-(lt.bindings.len() as i64))),
+            InstructionType::Break | InstructionType::Continue => {
+                let Some(lp) = self.loops.last_mut() else {
+                    throw_exception_span(&instruction.span, format!("'{}' can only be used inside of a loop", keyword(instruction)));
+                    unreachable!();
+                };
+                lp.exits.push((instruction, depth));
+                Depth::Diverged
+            }
+            InstructionType::Return => {
+                self.check_return(&instruction.span, depth);
+                Depth::Diverged
+            }
+            // Panics are left out of release builds
+            InstructionType::Panic(_) if self.options.release => depth,
+            InstructionType::Panic(_) => Depth::Diverged,
+            InstructionType::MacroCall(call) => {
+                let inline = expansion::called_inline(self.program, call, &instruction.span);
+                let block = self.expansion.enter(inline, &call.arguments, &instruction.span);
+                let depth = self.check_block(block, depth);
+                self.expansion.leave();
+                depth
+            }
+            InstructionType::Argument(_, index) => {
+                let scope = self.expansion.enter_argument();
+                let depth = self.check_block(&scope.arguments[*index], depth);
+                self.expansion.leave_argument(scope);
+                depth
+            }
+            InstructionType::Identifier(identifier) => {
+                if let Some(inline) = self.program.inlines.get(identifier) {
+                    let block = self.expansion.enter_plain(inline, &instruction.span);
+                    let depth = self.check_block(block, depth);
+                    self.expansion.leave();
+                    depth
+                } else if self.program.memories.contains_key(identifier) || self.program.datas.contains_key(identifier) {
+                    depth.apply(1)
+                } else if let Some(extern_proc) = self.program.externs.get(identifier) {
+                    depth.apply(extern_proc.effect.outputs.len() as i64 - extern_proc.effect.inputs.len() as i64)
+                } else {
+                    Depth::Unknown
+                }
+            }
+            other => depth.apply(other.pushes() as i64 - other.pops() as i64),
+        }
+    }
+
+    /// Exported procedures have to leave as many values as their stack effect declares
+    fn check_return(&self, span: &Span, depth: Depth) {
+        if let (Some((procedure, outputs)), Depth::Known(depth)) = (self.exported, depth) {
+            if depth != outputs {
+                throw_exception_span(span, format!("The stack holds {} here than the stack effect of exported procedure '{}' declares", difference(depth - outputs), procedure.identifier));
+            }
+        }
+    }
+
+    /// Checks the body of a loop, returns the depth after the loop. When the body changes the depth,
+    /// every iteration starts at a different depth, so its exits can not be verified.
+    fn check_loop(&mut self, block: &'a Block, start: Depth, break_depth: Depth, continue_depth: Depth) -> Depth {
+        self.loops.push(Loop { break_depth, continue_depth, exits: Vec::new() });
+        let body = self.check_block(block, break_depth);
+        let lp = self.loops.pop().unwrap();
+
+        if let (Depth::Known(start), Depth::Known(end)) = (start, body) {
+            if start != end {
+                return Depth::Unknown;
+            }
+        }
+        for (instruction, depth) in lp.exits {
+            let expected = match instruction.instruction_type {
+                InstructionType::Break => lp.break_depth,
+                _ => lp.continue_depth,
+            };
+            if let (Depth::Known(depth), Depth::Known(expected)) = (depth, expected) {
+                if depth != expected {
+                    throw_exception_span(&instruction.span, format!("The stack holds {} at this '{}' than the loop expects", difference(depth - expected), keyword(instruction)));
+                }
+            }
+        }
+        break_depth
+    }
+}
+
+fn keyword(instruction: &Instruction) -> &'static str {
+    match instruction.instruction_type {
+        InstructionType::Break => "break",
+        _ => "continue",
+    }
+}
+
+fn difference(values: i64) -> String {
+    if values > 0 {
+        format!("{} value(s) more", values)
+    } else {
+        format!("{} value(s) fewer", -values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::preprocessor;
+
+    /// Whether the source passes the stack checker
+    fn checks(source: &str) -> bool {
+        crate::recover_from_errors();
+        let options = CompilerOptions::default();
+        let source = source.to_string();
+        std::panic::catch_unwind(move || {
+            let mut l = Lexer::new(source, String::from("test.spl"));
+            l.tokenize();
+            let mut p = Parser::new(preprocessor::process(l.tokens, &preprocessor::defines(&options)));
+            p.parse();
+            check(&p.program, &options);
+        }).is_ok()
+    }
+
+    #[test]
+    fn while_continue_matches_the_loop_entry() {
+        assert!(checks("proc main do 0 while 0 = do 1 if 1 do continue end pop 1 end 42 put end"));
+        assert!(!checks("proc main do 0 while 0 = do 1 if 1 do 2 continue end pop 1 end 42 put end"));
+    }
+
+    #[test]
+    fn while_break_matches_the_loop_exit() {
+        assert!(checks("proc main do 0 while dup 3 < do if dup 1 = do break end 1 + end put end"));
+        assert!(!checks("proc main do 0 while dup 3 < do if dup 1 = do 5 break end 1 + end put end"));
+    }
+}
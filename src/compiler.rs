@@ -1,28 +1,157 @@
+use crate::expansion::{self, Expansion};
 use crate::operators::{InfixOperators};
-use crate::parser::{Block, Instruction, InstructionType, Procedure, PushType};
+use crate::parser::{Block, DataValue, Instruction, InstructionType, Procedure, PushType, StackEffect};
 use crate::program::Program;
+use crate::throw_exception_span;
+use crate::tokens::Span;
+
+/// How procedures are called and where the data stack lives
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum CallConvention {
+    /// Data stack on the hardware stack (`rsp`), return addresses on `ret_stack`, calls go through `call_proxy`
+    #[default]
+    Proxy,
+    /// Data stack in its own memory addressed by `rbp`, procedures use the hardware stack with `call`/`ret`
+    Native,
+}
+
+/// Settings of a build, passed on the command line
+#[derive(Debug, Default, Clone)]
+pub struct CompilerOptions {
+    /// Leaves out `assert` and `panic`
+    pub release: bool,
+    /// Checks the bounds of the data stack before instructions pop or push values
+    pub checked: bool,
+    /// Stops the program on division by zero and signed overflow of `+`, `-`, `*`, `/` and `%`
+    pub checked_arith: bool,
+    /// Amount of values the data stack can hold in checked builds and with native calls, `DATA_STACK_LIMIT` when not given
+    pub stack_limit: Option<usize>,
+    /// Entries of the return stack (the initial amount when it can grow), `RET_STACK_SIZE` when not given
+    pub ret_stack_size: Option<usize>,
+    /// Makes the return stack grow up to this amount of entries when it is full
+    pub ret_stack_max: Option<usize>,
+    /// Flags for conditional compilation given with `-D NAME=value`
+    pub defines: Vec<(String, String)>,
+    pub call_convention: CallConvention,
+    /// Compiles calls right before a `return` as normal calls, so every procedure shows up in backtraces
+    pub no_tco: bool,
+    /// Builds a static library of the exported procedures instead of an executable, without `_start`
+    pub library: bool,
+}
+
+/// Defaults of the start assembly, the interpreter and the VM apply the same limits
+pub const DATA_STACK_LIMIT: usize = 524288;
+pub const RET_STACK_SIZE: usize = 1024;
+
+impl CompilerOptions {
+    /// Values the data stack can hold
+    pub fn data_stack_limit(&self) -> usize {
+        self.stack_limit.unwrap_or(DATA_STACK_LIMIT)
+    }
+
+    /// Deepest nesting of procedure calls, the size of the return stack in compiled programs
+    pub fn call_limit(&self) -> usize {
+        self.ret_stack_max.or(self.ret_stack_size).unwrap_or(RET_STACK_SIZE)
+    }
+}
+
+/// Whether the generated assembly already defines `identifier`: a label or define of the start assembly,
+/// a label the compiler adds for procedures, strings and jump tables, or `exit` of libc, which runtime
+/// errors exit through. Exported procedures are global
+/// under their own name, so they can not be called like one of these.
+pub fn is_runtime_label(identifier: &str) -> bool {
+    let numbered = |prefix: &str| identifier.strip_prefix(prefix)
+        .is_some_and(|number| !number.is_empty() && number.bytes().all(|byte| byte.is_ascii_digit()));
+    if identifier.starts_with("proc_") || numbered("str_") || numbered("jump_table_")
+        || ["strings_start", "strings_end", "proc_table", "proc_table_end", "exit"].contains(&identifier)
+    {
+        return true;
+    }
+    include_str!("start_asm_x86_64.asm").lines().map(str::trim_start).any(|line| {
+        line.split_once(':').is_some_and(|(label, _)| label == identifier)
+            || line.strip_prefix("%define ").is_some_and(|define| define.split_whitespace().next() == Some(identifier))
+    })
+}
 
 pub struct Compiler {
     pub code: String,
     cursor: usize,
     program: Program,
+    options: CompilerOptions,
     strings: Vec<(String, String)>,
     label_count: usize, 
-    inline_expansion_stack: Vec<String>,
+    expansion: Expansion<Vec<Block>>,
+    /// (continue label, break label, aux depth) of the loops around the instruction being compiled
+    loop_stack: Vec<(usize, usize, usize)>,
+    /// Amount of entries the current procedure has pushed onto the auxiliary stack (`r15`)
+    aux_depth: usize,
+    /// Auxiliary stack offsets (relative to the procedure) where enclosing for loops saved `r12`/`r14`
+    for_stack: Vec<usize>,
+    /// (binding id, auxiliary stack offset relative to the procedure) of the lets in scope
+    bindings: Vec<(usize, usize)>,
+    /// Identifier of the procedure being compiled, local labels are qualified with it in jump tables
+    procedure: String,
+    /// (table id, target labels) of the jump tables of dense `match` instructions
+    jump_tables: Vec<(usize, Vec<String>)>,
+    /// (procedure identifier, id of its backtrace line) of every compiled procedure
+    proc_table: Vec<(String, usize)>,
 }
 
 impl Compiler {
-    pub fn new(program: Program) -> Compiler {
+    pub fn new(program: Program, options: CompilerOptions) -> Compiler {
+        let code = format!("{}{}\n", Compiler::defines(&program, &options), include_str!("start_asm_x86_64.asm"));
         Compiler {
             program,
             cursor: 0,
             strings: Vec::new(),
             label_count: 1,
-            code: String::from(format!("{}\n", include_str!("start_asm_x86_64.asm"))),
-            inline_expansion_stack: Vec::new(),
+            code,
+            expansion: Expansion::new(),
+            loop_stack: Vec::new(),
+            aux_depth: 0,
+            for_stack: Vec::new(),
+            bindings: Vec::new(),
+            procedure: String::new(),
+            jump_tables: Vec::new(),
+            proc_table: Vec::new(),
+            options,
         }
     }
 
+    /// `%define`s configuring the start assembly, which has defaults for everything left out
+    fn defines(program: &Program, options: &CompilerOptions) -> String {
+        let mut defines = String::new();
+        if Compiler::runs_with_libc(program, options) {
+            defines.push_str("%define LINK_LIBC\n");
+        }
+        if options.call_convention == CallConvention::Native {
+            defines.push_str("%define NATIVE_CALLS\n");
+        }
+        if options.library {
+            defines.push_str("%define LIBRARY\n");
+        }
+        if let Some(limit) = options.stack_limit {
+            defines.push_str(format!("%define DATA_STACK_LIMIT {}\n", limit).as_str());
+        }
+        if let Some(size) = options.ret_stack_size {
+            defines.push_str(format!("%define RET_STACK_SIZE {}\n", size).as_str());
+        }
+        if let Some(max) = options.ret_stack_max {
+            defines.push_str(format!("%define RET_STACK_MAX {}\n", max).as_str());
+        }
+        defines
+    }
+
+    /// Whether the program runs with libc, because it calls C functions or is a library for C programs
+    pub fn links_libc(&self) -> bool {
+        Compiler::runs_with_libc(&self.program, &self.options)
+    }
+
+    /// `links_libc` before the compiler exists
+    fn runs_with_libc(program: &Program, options: &CompilerOptions) -> bool {
+        !program.externs.is_empty() || options.library
+    }
+
     /// Generates a unique label ID and increments the counter
     fn next_label(&mut self) -> usize {
         let id = self.label_count;
@@ -36,18 +165,25 @@ impl Compiler {
         // 2. Then do the rest
         // 1. Handle main first
         if let Some(main_proc) = self.program.procedures.get("main") {
-            let ident = main_proc.identifier.clone(); 
-            let block = main_proc.block.clone();      
-            
-            self.add_proc(ident);
-            self.compile_block(&block);
+            let main_proc = main_proc.clone();
+
+            self.add_proc(main_proc.identifier.clone());
+            self.compile_block(&main_proc.block);
             
             // Global exit point
             self.code.push_str("\t; === GLOBAL EXIT ===\n");
-            self.add_instruction("mov rax, 60");
-            self.add_instruction("mov rdi, 0");
-            self.add_instruction("syscall\n");
-        } else {
+            if self.links_libc() {
+                // Exiting through libc flushes the buffers of stdio
+                self.add_instruction("xor edi, edi");
+                self.add_instruction("and rsp, -16");
+                self.add_instruction("call exit\n");
+            } else {
+                self.add_instruction("mov rax, 60");
+                self.add_instruction("mov rdi, 0");
+                self.add_instruction("syscall\n");
+            }
+            self.end_proc(&main_proc);
+        } else if !self.options.library {
             todo!("Throw error: No main procedure found");
         }
 
@@ -58,6 +194,27 @@ impl Compiler {
             
             self.add_proc(proc.identifier.clone());
             self.compile_block(&proc.block);
+            self.end_proc(proc);
+        }
+
+        // C-callable wrappers of the exported procedures
+        if self.options.library {
+            let mut exports: Vec<&Procedure> = procedures.iter().filter(|procedure| procedure.export.is_some()).collect();
+            exports.sort_by(|a, b| a.identifier.cmp(&b.identifier));
+            for procedure in exports {
+                self.add_export_wrapper(procedure);
+            }
+        }
+
+        // C functions, resolved when linking with libc
+        if self.links_libc() {
+            let mut externs: Vec<String> = self.program.externs.keys().cloned().collect();
+            externs.push(String::from("exit"));
+            externs.sort();
+            externs.dedup();
+            for identifier in externs {
+                self.code.push_str(format!("extern {}\n", identifier).as_str());
+            }
         }
 
         // BSS Section (Variables)
@@ -66,11 +223,37 @@ impl Compiler {
             if identifier == "argv" || identifier == "argc" { continue; }
             self.code.push_str(format!("\t{}: resb {}\n", memory.identifier, memory.size).as_str());
         }
-        self.code.push_str("\n");
+        self.code.push('\n');
 
-        // Data Section (Strings)
+        // Data Section (Initialized globals)
         self.code.push_str("section .data\n");
+        let datas: Vec<_> = self.program.datas.values().cloned().collect();
+        for data in &datas {
+            self.code.push_str(format!("\t{}:\n", data.identifier).as_str());
+            let directive = match data.width {
+                1 => "db",
+                2 => "dw",
+                4 => "dd",
+                _ => "dq",
+            };
+            for value in &data.values {
+                let line = match value {
+                    DataValue::Int(i) => format!("{} {}", directive, i),
+                    // Bytes are stored inline, wider data stores the string the same way it is pushed
+                    DataValue::Str(str, _) if data.width == 1 => format!("{}, 0", self.string_to_asm_data(str.clone())),
+                    DataValue::Str(str, original) => format!("dq {}, str_{}", str.len(), self.intern_string(str, original)),
+                };
+                self.add_instruction_string(line);
+            }
+        }
+        self.code.push('\n');
+
+        // Read-only Data Section (Strings)
+        // Literals live between `strings_start` and `strings_end`, so the SIGSEGV handler can tell a
+        // write into a string literal apart from any other invalid memory access.
+        self.code.push_str("section .rodata\n");
         self.code.push_str("; Strings with null terminators\n");
+        self.code.push_str("strings_start:\n");
         for (i, (str, original)) in self.strings.iter().enumerate() {
             self.code.push_str(
                 format!("\tstr_{}: {}, 0 ; \"{}\"\n", 
@@ -80,47 +263,87 @@ impl Compiler {
                 ).as_str()
             );
         }
+        self.code.push_str("strings_end:\n");
+
+        // Code address range of every procedure with its backtrace line, used by `print_backtrace`
+        self.code.push_str("proc_table:\n");
+        for (identifier, id) in &self.proc_table {
+            self.code.push_str(format!("\tdq proc_{0}, proc_{0}.proc_end, str_{1}, {2}\n", identifier, id, self.strings[*id].0.len()).as_str());
+        }
+        self.code.push_str("proc_table_end:\n");
+
+        // Jump tables of `match` instructions, one address per value between the lowest and highest case
+        for (id, labels) in &self.jump_tables {
+            self.code.push_str(format!("\tjump_table_{}: dq {}\n", id, labels.join(", ")).as_str());
+        }
     }
 
     fn compile_block(&mut self, block: &Block) {
-        for instruction in &block.instructions {
-            self.add_instruction_comment(&instruction);
+        for (index, instruction) in block.instructions.iter().enumerate() {
+            self.add_instruction_comment(instruction);
+
+            let (pops, pushes) = match &instruction.instruction_type {
+                // Checked right before they pop their condition, range or values
+                InstructionType::If(_) | InstructionType::While(_) | InstructionType::For(_) | InstructionType::Match(_) | InstructionType::Let(_) => (0, 0),
+                InstructionType::Identifier(identifier) if self.program.memories.contains_key(identifier) || self.program.datas.contains_key(identifier) => (0, 1),
+                InstructionType::Identifier(identifier) if self.program.externs.contains_key(identifier) => {
+                    let extern_proc = &self.program.externs[identifier];
+                    (extern_proc.effect.inputs.len(), extern_proc.effect.outputs.len())
+                }
+                InstructionType::Pick => (1, 1),
+                other => (other.pops() as usize, other.pushes() as usize),
+            };
+            self.check_stack(&instruction.span, pops, pushes);
 
             match &instruction.instruction_type {
                 InstructionType::Put => {
-                    self.add_instruction("pop rdi");
+                    self.pop("rdi");
                     self.add_instruction("call print_i64");
                 }
                 InstructionType::Push(PushType::Int(i)) => {
                     self.add_instruction_string(format!("mov rax, {}", i));
-                    self.add_instruction("push rax");
+                    self.push("rax");
                 }
                 InstructionType::Push(PushType::Str(str, original)) => {
                     // Pushes [length, address]
-                    self.add_instruction_string(format!("push {}", str.len()));
-                    self.add_instruction_string(format!("push str_{}", self.strings.len()));
-                    self.strings.push((str.clone(), original.clone()));
+                    let id = self.intern_string(str, original);
+                    self.push(&str.len().to_string());
+                    self.push(&format!("str_{}", id));
                 }
                 InstructionType::InfixOperators(op) => {
-                    self.add_instruction("pop rbx"); // Right operand
-                    self.add_instruction("pop rax"); // Left operand
+                    self.pop("rbx"); // Right operand
+                    self.pop("rax"); // Left operand
 
                     match op {
-                        InfixOperators::Plus | InfixOperators::Minus  => {
+                        InfixOperators::Plus | InfixOperators::Minus | InfixOperators::Multiply => {
                             self.add_instruction_string(format!("{} rax, rbx", op.to_x86_64_instruction()));
-                            self.add_instruction("push rax");
-                        }
-                        InfixOperators::Multiply | InfixOperators::Divide => {
-                            // Multiply/Divide use RAX implicitly
-                            self.add_instruction("xor rdx, rdx");
-                            self.add_instruction_string(format!("{} rbx", op.to_x86_64_instruction()));
-                            self.add_instruction("push rax");
+                            if self.options.checked_arith {
+                                self.add_check("jno", &instruction.span, &format!("signed overflow in '{}'", op.symbol()));
+                            }
+                            self.push("rax");
                         }
-                        InfixOperators::Modulo => {
-                            self.add_instruction("xor rdx, rdx");
+                        InfixOperators::Divide | InfixOperators::Modulo => {
+                            if self.options.checked_arith {
+                                self.add_instruction("test rbx, rbx");
+                                self.add_check("jnz", &instruction.span, "division by zero");
+
+                                // The only signed division that overflows: -2^63 / -1
+                                let label = self.next_label();
+                                self.add_instruction("cmp rbx, -1");
+                                self.add_instruction_string(format!("jne .addr_{}", label));
+                                self.add_instruction("mov rcx, 0x8000000000000000");
+                                self.add_instruction("cmp rax, rcx");
+                                self.add_check("jne", &instruction.span, &format!("signed overflow in '{}'", op.symbol()));
+                                self.add_label(label);
+                            }
+
+                            // Signed division of rdx:rax, the quotient ends up in rax and the remainder in rdx
                             self.add_instruction("cqo");
-                            self.add_instruction_string(format!("idiv rbx"));
-                            self.add_instruction("push rdx");
+                            self.add_instruction("idiv rbx");
+                            match op {
+                                InfixOperators::Divide => self.push("rax"),
+                                _ => self.push("rdx"),
+                            }
                         }
                         InfixOperators::And => {
                             self.add_instruction("cmp rax, 0");
@@ -129,18 +352,18 @@ impl Compiler {
                             self.add_instruction("setne bl");
                             self.add_instruction_string(format!("{} al, bl", op.to_x86_64_instruction()));
                             self.add_instruction("movzx rax, al");
-                            self.add_instruction("push rax");
+                            self.push("rax");
                         }
                         InfixOperators::Or => {
                             self.add_instruction_string(format!("{} rax, rbx", op.to_x86_64_instruction()));
-                            self.add_instruction("push rax");
+                            self.push("rax");
                         }
                         _ => {
                             // Comparison operators
                             self.add_instruction("xor rcx, rcx");
                             self.add_instruction("cmp rax, rbx");
                             self.add_instruction_string(format!("{} cl", op.to_x86_64_instruction()));
-                            self.add_instruction("push rcx");
+                            self.push("rcx");
                         }
                     };
                 }
@@ -151,21 +374,172 @@ impl Compiler {
                     self.add_label(start_label);
                     self.compile_block(&whl.condition);
 
-                    self.add_instruction("pop rax");
+                    self.check_stack(&instruction.span, 1, 0);
+                    self.pop("rax");
                     self.add_instruction("cmp rax, 0");
                     self.add_instruction_string(format!("je .addr_{}", end_label));
 
+                    self.loop_stack.push((start_label, end_label, self.aux_depth));
                     self.compile_block(&whl.block);
+                    self.loop_stack.pop();
+                    self.add_instruction_string(format!("jmp .addr_{}", start_label));
+                    self.add_label(end_label);
+                }
+                InstructionType::For(fr) => {
+                    // The index lives in r12 and the end in r14, the values of an enclosing loop
+                    // are saved on the auxiliary stack and restored once the loop is done
+                    let start_label = self.next_label();
+                    let next_label = self.next_label();
+                    let end_label = self.next_label();
+
+                    self.compile_block(&fr.range);
+                    self.check_stack(&instruction.span, 2, 0);
+                    self.add_instruction("cmp r15, AUX_STACK_SIZE - 2");
+                    self.add_instruction("jg aux_stack_overflow");
+                    self.add_instruction("mov [aux_stack + r15 * 8], r12");
+                    self.add_instruction("mov [aux_stack + r15 * 8 + 8], r14");
+                    self.add_instruction("add r15, 2");
+                    self.pop("r14");
+                    self.pop("r12");
+                    self.for_stack.push(self.aux_depth);
+                    self.aux_depth += 2;
+                    self.expansion.enter_loop();
+
+                    self.add_label(start_label);
+                    self.add_instruction("cmp r12, r14");
+                    self.add_instruction_string(format!("jge .addr_{}", end_label));
+
+                    self.loop_stack.push((next_label, end_label, self.aux_depth));
+                    self.compile_block(&fr.block);
+                    self.loop_stack.pop();
+
+                    self.add_label(next_label);
+                    self.add_instruction("inc r12");
                     self.add_instruction_string(format!("jmp .addr_{}", start_label));
                     self.add_label(end_label);
+
+                    self.expansion.leave_loop();
+                    self.aux_depth -= 2;
+                    self.for_stack.pop();
+                    self.add_instruction("sub r15, 2");
+                    self.add_instruction("mov r12, [aux_stack + r15 * 8]");
+                    self.add_instruction("mov r14, [aux_stack + r15 * 8 + 8]");
                 }
+                InstructionType::Let(lt) => {
+                    // Bound values are kept on the auxiliary stack, the first binding at the lowest entry
+                    let count = lt.bindings.len();
+                    self.check_stack(&instruction.span, count, 0);
+                    self.add_instruction_string(format!("cmp r15, AUX_STACK_SIZE - {}", count));
+                    self.add_instruction("jg aux_stack_overflow");
+                    for (i, binding) in lt.bindings.iter().enumerate().rev() {
+                        self.pop("rax");
+                        self.add_instruction_string(format!("mov [aux_stack + r15 * 8 + {}], rax", i * 8));
+                        self.bindings.push((binding.id, self.aux_depth + i));
+                    }
+                    self.add_instruction_string(format!("add r15, {}", count));
+                    self.aux_depth += count;
+
+                    self.compile_block(&lt.block);
+
+                    self.aux_depth -= count;
+                    self.bindings.truncate(self.bindings.len() - count);
+                    self.add_instruction_string(format!("sub r15, {}", count));
+                }
+                InstructionType::Binding(binding) => {
+                    let Some((_, offset)) = self.bindings.iter().rev().find(|(id, _)| *id == binding.id) else {
+                        throw_exception_span(&instruction.span, format!("'{}' is not bound here", binding.identifier));
+                        unreachable!();
+                    };
+                    let offset = (self.aux_depth - offset) * 8;
+                    self.add_instruction_string(format!("mov rax, [aux_stack + r15 * 8 - {}]", offset));
+                    self.push("rax");
+                }
+                InstructionType::Match(mtch) => {
+                    let end_label = self.next_label();
+                    let else_label = if mtch.else_block.is_some() { self.next_label() } else { end_label };
+                    let case_labels: Vec<usize> = mtch.cases.iter().map(|_| self.next_label()).collect();
+
+                    self.compile_block(&mtch.value);
+                    self.check_stack(&instruction.span, 1, 0);
+                    self.pop("rax");
+
+                    // Dense cases index a jump table, sparse cases are compared one by one
+                    let min = mtch.cases.iter().map(|(value, _)| *value).min().unwrap_or(0);
+                    let max = mtch.cases.iter().map(|(value, _)| *value).max().unwrap_or(0);
+                    let range = max as i128 - min as i128 + 1;
+                    if mtch.cases.len() >= 3 && range <= 2 * mtch.cases.len() as i128 {
+                        let table = self.next_label();
+                        let labels = (0..range as i64).map(|offset| {
+                            let target = match mtch.cases.iter().position(|(value, _)| *value == min + offset) {
+                                Some(case) => case_labels[case],
+                                None => else_label,
+                            };
+                            format!("proc_{}.addr_{}", self.procedure, target)
+                        }).collect();
+                        self.jump_tables.push((table, labels));
+
+                        self.add_instruction_string(format!("mov rbx, {}", min));
+                        self.add_instruction("sub rax, rbx");
+                        self.add_instruction_string(format!("cmp rax, {}", range - 1));
+                        self.add_instruction_string(format!("ja .addr_{}", else_label)); // Unsigned, so values below the lowest case are caught too
+                        self.add_instruction_string(format!("jmp [jump_table_{} + rax * 8]", table));
+                    } else {
+                        for ((value, _), label) in mtch.cases.iter().zip(&case_labels) {
+                            if i32::try_from(*value).is_ok() {
+                                self.add_instruction_string(format!("cmp rax, {}", value));
+                            } else {
+                                self.add_instruction_string(format!("mov rbx, {}", value));
+                                self.add_instruction("cmp rax, rbx");
+                            }
+                            self.add_instruction_string(format!("je .addr_{}", label));
+                        }
+                        self.add_instruction_string(format!("jmp .addr_{}", else_label));
+                    }
+
+                    for ((_, block), label) in mtch.cases.iter().zip(&case_labels) {
+                        self.add_label(*label);
+                        self.compile_block(block);
+                        self.add_instruction_string(format!("jmp .addr_{}", end_label));
+                    }
+                    if let Some(else_block) = &mtch.else_block {
+                        self.add_label(else_label);
+                        self.compile_block(else_block);
+                    }
+                    self.add_label(end_label);
+                }
+                InstructionType::Assert => {
+                    if self.options.release {
+                        self.pop("rax"); // The condition is still consumed
+                    } else {
+                        self.pop("rax");
+                        self.add_instruction("test rax, rax");
+                        self.add_check("jnz", &instruction.span, "assertion failed");
+                    }
+                }
+                InstructionType::Panic(message) => {
+                    if !self.options.release {
+                        self.add_runtime_error(&instruction.span, message);
+                    }
+                }
+                InstructionType::Index => match self.expansion.index_depth() {
+                    None => throw_exception_span(&instruction.span, "'i' can only be used inside of a for loop".to_string()),
+                    Some(0) => self.push("r12"),
+                    Some(depth) => {
+                        // An `i` passed to an inline refers to a loop outside of the loops of the inline,
+                        // its index was saved by the loop nested in it
+                        let saved = self.for_stack[self.for_stack.len() - depth];
+                        self.add_instruction_string(format!("mov rax, [aux_stack + r15 * 8 - {}]", (self.aux_depth - saved) * 8));
+                        self.push("rax");
+                    }
+                },
                 InstructionType::If(iff) => {
                     let end_label = self.next_label();
                     
                     // --- Compile IF ---
                     let next_branch_label = self.next_label();
                     self.compile_block(&iff.if_block.0); // Condition
-                    self.add_instruction("pop rax");
+                    self.check_stack(&instruction.span, 1, 0);
+                    self.pop("rax");
                     self.add_instruction("cmp rax, 0");
                     self.add_instruction_string(format!("je .addr_{}", next_branch_label));
                     
@@ -177,7 +551,8 @@ impl Compiler {
                     for (cond, body) in &iff.elif_blocks {
                         let next_elif_label = self.next_label();
                         self.compile_block(cond);
-                        self.add_instruction("pop rax");
+                        self.check_stack(&instruction.span, 1, 0);
+                        self.pop("rax");
                         self.add_instruction("cmp rax, 0");
                         self.add_instruction_string(format!("je .addr_{}", next_elif_label));
                         
@@ -194,51 +569,58 @@ impl Compiler {
                     self.add_label(end_label);
                 }
                 InstructionType::Pop => {
-                    self.add_instruction("pop rax");
+                    self.pop("rax");
                 }
                 InstructionType::Dup => {
-                    self.add_instruction("pop rax");
-                    self.add_instruction("push rax");
-                    self.add_instruction("push rax");
+                    self.pop("rax");
+                    self.push("rax");
+                    self.push("rax");
                 }  
                 InstructionType::Over => {
                     // ( a b -- a b a )
-                    self.add_instruction("pop rax");
-                    self.add_instruction("pop rbx");
-                    self.add_instruction("push rbx");
-                    self.add_instruction("push rax");
-                    self.add_instruction("push rbx");
+                    self.pop("rax");
+                    self.pop("rbx");
+                    self.push("rbx");
+                    self.push("rax");
+                    self.push("rbx");
                 }
                 InstructionType::Pick => {
-                    self.add_instruction("pop rax");          
+                    self.pop("rax");          
                     self.add_instruction("shl rax, 3");       // rax = N * 8 (shift left by 3 is same as * 8)
-                    self.add_instruction("mov rbx, [rsp + rax]"); // Get the value at that memory offset
-                    self.add_instruction("push rbx");         
+                    if self.options.checked {
+                        // The picked item has to be on the stack, negative indices are caught by the unsigned compare
+                        self.add_instruction("mov rbx, [ori_stack_ptr]");
+                        self.add_instruction_string(format!("sub rbx, {}", self.stack_pointer()));
+                        self.add_instruction("cmp rax, rbx");
+                        self.add_check("jb", &instruction.span, "data stack underflow, pick index is out of bounds");
+                    }
+                    self.add_instruction_string(format!("mov rbx, [{} + rax]", self.stack_pointer())); // Get the value at that memory offset
+                    self.push("rbx");         
                 }             
                 InstructionType::Swap => {
-                    self.add_instruction("pop rax");
-                    self.add_instruction("pop rbx");
-                    self.add_instruction("push rax");
-                    self.add_instruction("push rbx");
+                    self.pop("rax");
+                    self.pop("rbx");
+                    self.push("rax");
+                    self.push("rbx");
                 }
                 InstructionType::Rot => {
                     // ( a b c -- b c a )
-                    self.add_instruction("pop rcx");
-                    self.add_instruction("pop rbx");
-                    self.add_instruction("pop rax");
-                    self.add_instruction("push rbx");
-                    self.add_instruction("push rcx");
-                    self.add_instruction("push rax");
+                    self.pop("rcx");
+                    self.pop("rbx");
+                    self.pop("rax");
+                    self.push("rbx");
+                    self.push("rcx");
+                    self.push("rax");
                 }
                 InstructionType::Size => {
-                    self.add_instruction("mov rax, rsp");
+                    self.add_instruction_string(format!("mov rax, {}", self.stack_pointer()));
                     self.add_instruction("sub rax, [ori_stack_ptr]");
                     self.add_instruction("neg rax");
                     self.add_instruction("shr rax, 3"); // Divide by 8 bytes
-                    self.add_instruction("push rax");
+                    self.push("rax");
                 }
                 InstructionType::Load(size) => {
-                    self.add_instruction("pop rax");
+                    self.pop("rax");
                     self.add_instruction("xor rbx, rbx");
                     match size {
                         1 => self.add_instruction("mov bl, [rax]"),
@@ -247,11 +629,11 @@ impl Compiler {
                         8 => self.add_instruction("mov rbx, [rax]"),
                         _ => panic!("Unsupported load size"),
                     };
-                    self.add_instruction("push rbx");
+                    self.push("rbx");
                 }
                 InstructionType::Store(size) => {
-                    self.add_instruction("pop rbx");
-                    self.add_instruction("pop rax");
+                    self.pop("rbx");
+                    self.pop("rax");
                     match size {
                         1 => self.add_instruction("mov [rax], bl"),
                         2 => self.add_instruction("mov [rax], bx"),
@@ -262,25 +644,64 @@ impl Compiler {
                 }
                 InstructionType::Syscall(arg_count) => {
                     let regs = ["rax", "rdi", "rsi", "rdx", "r10", "r9", "r8"];
-                    for i in 0..*arg_count as usize {
-                        self.add_instruction_string(format!("pop {}", regs[i]));
+                    for reg in regs.iter().take(*arg_count as usize) {
+                        self.pop(reg);
                     }
                     self.add_instruction("syscall");
-                    self.add_instruction("push rax"); // Capture result
+                    self.push("rax"); // Capture result
+                }
+                InstructionType::MacroCall(call) => {
+                    let inline = expansion::called_inline(&self.program, call, &instruction.span).clone();
+                    let block = self.expansion.enter(&inline, call.arguments.clone(), &instruction.span);
+                    self.compile_block(block);
+                    self.expansion.leave();
+                }
+                InstructionType::Argument(_, index) => {
+                    // Arguments are compiled as if they were written at the call site, so a parameter
+                    // or inline used by an argument is the one of the caller, not of the inline. Labels
+                    // are unique per expansion and bindings are resolved by id, so neither can clash.
+                    let scope = self.expansion.enter_argument();
+                    self.compile_block(&scope.arguments[*index]);
+                    self.expansion.leave_argument(scope);
+                }
+                InstructionType::Asm(asm) => {
+                    // A context per block makes %$labels unique, even when an inline expands it twice
+                    self.add_instruction("%push asm");
+                    for line in &asm.lines {
+                        self.add_instruction(line);
+                    }
+                    self.add_instruction("%pop");
                 }
                 InstructionType::Identifier(identifier) => {
-                    if let Some(inline) = self.program.inlines.get(identifier) {
-                        if self.inline_expansion_stack.contains(identifier) {
-                            todo!("Implement error for inline expansion stack")
+                    if let Some(inline) = self.program.inlines.get(identifier).cloned() {
+                        let block = self.expansion.enter_plain(&inline, &instruction.span);
+                        self.compile_block(block);
+                        self.expansion.leave();
+                    } else if self.program.memories.contains_key(identifier) || self.program.datas.contains_key(identifier) {
+                        self.push(identifier);
+                    } else if let Some(extern_proc) = self.program.externs.get(identifier).cloned() {
+                        // System V ABI: arguments in registers, the first argument is the deepest on the stack
+                        let registers = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+                        for register in registers.iter().take(extern_proc.effect.inputs.len()).rev() {
+                            self.pop(register);
                         }
-
-                        self.inline_expansion_stack.push(identifier.clone());
-
-                        self.compile_block(&inline.block.clone());
-
-                        self.inline_expansion_stack.pop();
-                    } else if self.program.memories.contains_key(identifier) {
-                        self.add_instruction(format!("push {}", identifier).as_str());
+                        self.add_instruction("mov rbx, rsp");  // rbx is preserved by C functions
+                        self.add_instruction("and rsp, -16"); // The stack has to be 16 byte aligned at a call
+                        self.add_instruction("xor eax, eax"); // No vector registers hold arguments of variadic functions
+                        self.add_instruction_string(format!("call {}", identifier));
+                        self.add_instruction("mov rsp, rbx");
+                        if let Some(output) = extern_proc.effect.outputs.first() {
+                            if output == "int" {
+                                self.add_instruction("movsxd rax, eax"); // The upper half of rax is undefined for a C int
+                            }
+                            self.push("rax");
+                        }
+                    } else if self.program.procedures.contains_key(identifier) && !self.options.no_tco && is_return(block.instructions.get(index + 1)) {
+                        // Tail call, the callee returns straight to our caller with the return address that is already saved
+                        self.unwind_aux_stack();
+                        self.add_instruction_string(format!("jmp proc_{}", identifier));
+                    } else if self.program.procedures.contains_key(identifier) && self.options.call_convention == CallConvention::Native {
+                        self.add_instruction_string(format!("call proc_{}", identifier));
                     } else if self.program.procedures.contains_key(identifier) {
                         let label: usize = self.next_label();
                         self.add_instruction_string(format!("mov rdi, proc_{}", identifier));
@@ -292,12 +713,39 @@ impl Compiler {
                         todo!("Implement actual error message and exit program")
                     }
                 }
+                InstructionType::Break => {
+                    let Some(&(_, end_label, depth)) = self.loop_stack.last() else {
+                        throw_exception_span(&instruction.span, "'break' can only be used inside of a loop".to_string());
+                        unreachable!();
+                    };
+                    self.unwind_bindings(depth);
+                    self.add_instruction_string(format!("jmp .addr_{}", end_label));
+                }
+                InstructionType::Continue => {
+                    let Some(&(start_label, _, depth)) = self.loop_stack.last() else {
+                        throw_exception_span(&instruction.span, "'continue' can only be used inside of a loop".to_string());
+                        unreachable!();
+                    };
+                    self.unwind_bindings(depth);
+                    self.add_instruction_string(format!("jmp .addr_{}", start_label));
+                }
                 InstructionType::Return => {
-                    self.add_instruction("test r13, r13");
-                    self.add_instruction("jz stack_underflow");
-                    self.add_instruction("mov rdx, [ret_stack + r13 * 8]");
-                    self.add_instruction("dec r13");
-                    self.add_instruction("jmp rdx");
+                    self.unwind_aux_stack();
+                    match self.options.call_convention {
+                        CallConvention::Native => self.add_instruction("ret"),
+                        CallConvention::Proxy => {
+                            self.add_instruction("test r13, r13");
+                            self.add_instruction("jz stack_underflow");
+                            if self.options.ret_stack_max.is_some() {
+                                self.add_instruction("mov rcx, [ret_stack_ptr]");
+                                self.add_instruction("mov rdx, [rcx + r13 * 8]");
+                            } else {
+                                self.add_instruction("mov rdx, [ret_stack + r13 * 8]");
+                            }
+                            self.add_instruction("dec r13");
+                            self.add_instruction("jmp rdx");
+                        }
+                    }
                 }
             }
             self.cursor += 1;
@@ -306,6 +754,92 @@ impl Compiler {
 
     // --- Helper Functions ---
 
+    /// Drops everything the current procedure pushed onto the auxiliary stack, restoring the
+    /// for loop registers of the caller. Used when returning from within loops.
+    fn unwind_aux_stack(&mut self) {
+        if let Some(outer_for) = self.for_stack.first() {
+            let offset = (self.aux_depth - outer_for) * 8;
+            self.add_instruction_string(format!("mov r12, [aux_stack + r15 * 8 - {}]", offset));
+            self.add_instruction_string(format!("mov r14, [aux_stack + r15 * 8 - {}]", offset - 8));
+        }
+        if self.aux_depth > 0 {
+            self.add_instruction_string(format!("sub r15, {}", self.aux_depth));
+        }
+    }
+
+    /// Drops the let bindings pushed since the auxiliary stack was at `depth`, used when jumping out of them
+    fn unwind_bindings(&mut self, depth: usize) {
+        if self.aux_depth > depth {
+            self.add_instruction_string(format!("sub r15, {}", self.aux_depth - depth));
+        }
+    }
+
+    /// Bounds checks of `--checked` builds, before an instruction pops `pops` values and pushes `pushes` values
+    fn check_stack(&mut self, span: &Span, pops: usize, pushes: usize) {
+        if !self.options.checked {
+            return;
+        }
+
+        if pops > 0 {
+            self.add_instruction_string(format!("lea rax, [{} + {}]", self.stack_pointer(), pops * 8));
+            self.add_instruction("cmp rax, [ori_stack_ptr]");
+            self.add_check("jbe", span, &format!("data stack underflow, {} value(s) needed", pops));
+        }
+        if pushes > pops {
+            self.add_instruction_string(format!("lea rax, [{} - {}]", self.stack_pointer(), (pushes - pops) * 8));
+            self.add_instruction("cmp rax, [data_stack_limit]");
+            self.add_check("jae", span, "data stack overflow");
+        }
+    }
+
+    /// Runtime check, stops the program with the message unless the conditional jump `jump_if_ok` is taken
+    fn add_check(&mut self, jump_if_ok: &str, span: &Span, message: &str) {
+        let label = self.next_label();
+        self.add_instruction_string(format!("{} .addr_{}", jump_if_ok, label));
+        self.add_runtime_error(span, message);
+        self.add_label(label);
+    }
+
+    /// Prints `file:line:col: <message>` and a backtrace to stderr and exits with a non-zero code
+    fn add_runtime_error(&mut self, span: &Span, message: &str) {
+        let message = format!("{}:{}:{}: {}\n", span.file, span.line, span.column, message);
+        let id = self.intern_string(&message, &message.escape_default().to_string());
+        self.add_instruction_string(format!("mov rsi, str_{}", id));
+        self.add_instruction_string(format!("mov rdx, {}", message.len()));
+        self.add_instruction("lea rbx, [rel $]"); // Address of the error, the first line of the backtrace
+        self.add_instruction("jmp runtime_error");
+    }
+
+    /// Register pointing at the top of the data stack
+    fn stack_pointer(&self) -> &'static str {
+        match self.options.call_convention {
+            CallConvention::Proxy => "rsp",
+            CallConvention::Native => "rbp",
+        }
+    }
+
+    /// Pushes a register, immediate or label onto the data stack
+    fn push(&mut self, operand: &str) {
+        match self.options.call_convention {
+            CallConvention::Proxy => self.add_instruction_string(format!("push {}", operand)),
+            CallConvention::Native => {
+                self.add_instruction("lea rbp, [rbp - 8]"); // lea leaves the flags alone, like push
+                self.add_instruction_string(format!("mov QWORD [rbp], {}", operand));
+            }
+        }
+    }
+
+    /// Pops the top of the data stack into a register
+    fn pop(&mut self, register: &str) {
+        match self.options.call_convention {
+            CallConvention::Proxy => self.add_instruction_string(format!("pop {}", register)),
+            CallConvention::Native => {
+                self.add_instruction_string(format!("mov {}, [rbp]", register));
+                self.add_instruction("lea rbp, [rbp + 8]");
+            }
+        }
+    }
+
     fn add_instruction(&mut self, instruction: &str) {
         self.code.push_str(format!("\t{}\n", instruction).as_str());
     }
@@ -323,13 +857,180 @@ impl Compiler {
     }
 
     fn add_proc(&mut self, ident: String) {
+        self.procedure = ident.clone();
         self.code.push_str(format!("\nproc_{}:\n", ident).as_str());
     }
 
+    /// Adds `$<identifier>:`, which C calls with the System V ABI. It saves the registers C expects to be
+    /// preserved, starts a fresh data and return stack, pushes the arguments, calls the procedure and
+    /// returns the top of the stack in `rax`. The stacks are global, so it can not be called again while
+    /// a procedure is running, e.g. by a C function the procedure called.
+    fn add_export_wrapper(&mut self, procedure: &Procedure) {
+        let effect = procedure.export.as_ref().expect("Only exported procedures have a wrapper");
+        let registers = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+
+        // `$` makes NASM read the name as a label even when it is an instruction or register, like `add`
+        self.code.push_str(format!("\nglobal ${0}\n${0}:\n", procedure.identifier).as_str());
+        for register in ["rbx", "rbp", "r12", "r13", "r14", "r15"] {
+            self.add_instruction_string(format!("push {}", register));
+        }
+        self.add_instruction("push QWORD [ori_stack_ptr]");
+        self.add_instruction("push QWORD [data_stack_limit]");
+        self.add_instruction("xor r13, r13");
+        self.add_instruction("xor r15, r15");
+
+        match self.options.call_convention {
+            CallConvention::Proxy => {
+                // The data stack grows down from here, below the frame of the C caller
+                self.add_instruction("mov [ori_stack_ptr], rsp");
+                self.add_instruction("lea rax, [rsp - DATA_STACK_LIMIT * 8]");
+                self.add_instruction("mov [data_stack_limit], rax");
+                for register in registers.iter().take(effect.inputs.len()) {
+                    self.push(register);
+                }
+                self.add_instruction_string(format!("mov rdi, proc_{}", procedure.identifier));
+                self.add_instruction("mov rax, .return");
+                self.add_instruction("jmp call_proxy");
+                self.code.push_str(".return:\n");
+            }
+            CallConvention::Native => {
+                // The data stack is mapped by the first call, with a guard page like in executables
+                self.add_instruction("mov rax, [data_stack_top]");
+                self.add_instruction("test rax, rax");
+                self.add_instruction("jnz .mapped");
+                self.add_instruction("call map_data_stack");
+                self.add_instruction("mov [data_stack_top], rax");
+                self.code.push_str(".mapped:\n");
+                self.add_instruction("mov rbp, rax");
+                self.add_instruction("mov [ori_stack_ptr], rbp");
+                self.add_instruction("sub rax, DATA_STACK_LIMIT * 8");
+                self.add_instruction("mov [data_stack_limit], rax");
+                for register in registers.iter().take(effect.inputs.len()) {
+                    self.push(register);
+                }
+                self.add_instruction("mov [call_stack_top], rsp");
+                self.add_instruction_string(format!("call proc_{}", procedure.identifier));
+            }
+        }
+
+        if effect.outputs.is_empty() {
+            self.add_instruction("xor eax, eax");
+        } else {
+            self.pop("rax");
+        }
+        if self.options.call_convention == CallConvention::Proxy {
+            self.add_instruction("mov rsp, [ori_stack_ptr]"); // Drops what the procedure left on the stack
+        }
+        self.add_instruction("pop QWORD [data_stack_limit]");
+        self.add_instruction("pop QWORD [ori_stack_ptr]");
+        for register in ["r15", "r14", "r13", "r12", "rbp", "rbx"] {
+            self.add_instruction_string(format!("pop {}", register));
+        }
+        self.add_instruction("ret");
+    }
+
+    /// C declarations of the exported procedures, written next to the library in `--lib` builds
+    pub fn c_header(&self) -> String {
+        let mut exports: Vec<(&String, &StackEffect)> = self.program.procedures.values()
+            .filter_map(|procedure| procedure.export.as_ref().map(|effect| (&procedure.identifier, effect)))
+            .collect();
+        exports.sort_by(|a, b| a.0.cmp(b.0));
+
+        let mut header = String::from("// Generated by stapel build --lib\n#pragma once\n#include <stdint.h>\n\n");
+        for (identifier, effect) in exports {
+            let result = if effect.outputs.is_empty() { String::from("void") } else { format!("int64_t /* {} */", effect.outputs[0]) };
+            let arguments = if effect.inputs.is_empty() {
+                String::from("void")
+            } else {
+                effect.inputs.iter().map(|input| format!("int64_t /* {} */", input)).collect::<Vec<_>>().join(", ")
+            };
+            header.push_str(format!("{} {}({});\n", result, identifier, arguments).as_str());
+        }
+        header
+    }
+
+    /// Marks the end of the code of a procedure and adds it to the procedure table
+    fn end_proc(&mut self, procedure: &Procedure) {
+        self.code.push_str(".proc_end:\n");
+        let line = format!("\tat {} ({}:{}:{})\n", procedure.identifier, procedure.span.file, procedure.span.line, procedure.span.column);
+        let id = self.intern_string(&line, &line.escape_default().to_string());
+        self.proc_table.push((procedure.identifier.clone(), id));
+    }
+
+    /// Returns the id of the `str_<id>` label holding this literal, only adding it when it is not known yet
+    fn intern_string(&mut self, str: &str, original: &str) -> usize {
+        if let Some(id) = self.strings.iter().position(|(s, _)| s == str) {
+            return id;
+        }
+
+        self.strings.push((str.to_string(), original.to_string()));
+        self.strings.len() - 1
+    }
+
     fn string_to_asm_data(&self, s: String) -> String {
         if s.is_empty() { return "db 0".to_string(); }
         let mut s2 = String::new();
-        s.chars().for_each(|c| s2.push_str(format!(",0x{:x}", c as i32).as_str()));
+        s.bytes().for_each(|b| s2.push_str(format!(",0x{:x}", b).as_str()));
         format!("db {}", &s2[1..])
     }
-}
\ No newline at end of file
+}
+
+/// Whether the instruction is a `return`, explicit or the one `Procedure::parse` adds at the end
+fn is_return(instruction: Option<&Instruction>) -> bool {
+    instruction.is_some_and(|instruction| instruction.instruction_type == InstructionType::Return)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::{checker, preprocessor};
+
+    /// Compiles the source to assembly, exits with an error like a build
+    fn compile(source: String) -> String {
+        build(source, CompilerOptions::default())
+    }
+
+    fn build(source: String, options: CompilerOptions) -> String {
+        crate::recover_from_errors();
+        let mut l = Lexer::new(source, String::from("test.spl"));
+        l.tokenize();
+        let mut p = Parser::new(preprocessor::process(l.tokens, &preprocessor::defines(&options)));
+        if options.library {
+            p.parse_library();
+        } else {
+            p.parse();
+        }
+        checker::check(&p.program, &options);
+        let mut compiler = Compiler::new(p.program, options);
+        compiler.compile_x86_64();
+        compiler.code
+    }
+
+    /// std.spl is not included by the compiler, compiling it checks its `static_assert`s
+    #[test]
+    fn std_compiles() {
+        compile(format!("{}\nproc main do 0 exit end\n", include_str!("../std/std.spl")));
+    }
+
+    /// The index of the loop around the call is saved on the auxiliary stack by the loop of the inline
+    #[test]
+    fn arguments_see_the_loops_of_the_call_site() {
+        let code = compile(String::from("inline loopy(body) for 0 2 do body end end\nproc main do for 10 12 do loopy(i) put end end\n"));
+        assert!(code.contains("mov rax, [aux_stack + r15 * 8 - 16]"));
+    }
+
+    /// `$` lets an export be named like an instruction, names of the runtime are rejected
+    #[test]
+    fn export_names() {
+        let library = |name: &str| {
+            let source = format!("export proc {} a b -- sum do + end\n", name);
+            std::panic::catch_unwind(|| build(source, CompilerOptions { library: true, ..CompilerOptions::default() }))
+        };
+        assert!(library("add").unwrap().contains("global $add\n$add:\n"));
+        for name in ["print_i64", "call_proxy", "exit", "runtime_error", "argc", "proc_table", "proc_add", "str_0", "DATA_STACK_LIMIT"] {
+            assert!(library(name).is_err(), "'{}' was exported", name);
+        }
+    }
+}
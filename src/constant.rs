@@ -0,0 +1,101 @@
+use crate::expansion::Expansion;
+use crate::parser::{Block, Instruction, InstructionType, MacroCall, PushType};
+use crate::program::Program;
+use crate::throw_exception_span;
+
+/// Evaluates a block at compile time and returns the resulting stack (top of stack last).
+/// Only integer literals, infix operators, stack manipulation and inlines which are constant
+/// themselves are allowed, anything else exits with an error at the offending instruction.
+pub fn evaluate(program: &Program, block: &Block) -> Vec<i64> {
+    let mut evaluator = ConstEvaluator { program, stack: Vec::new(), expansion: Expansion::new() };
+    evaluator.evaluate_block(block);
+    evaluator.stack
+}
+
+struct ConstEvaluator<'a> {
+    program: &'a Program,
+    stack: Vec<i64>,
+    expansion: Expansion<&'a [Block]>,
+}
+
+impl<'a> ConstEvaluator<'a> {
+    fn evaluate_block(&mut self, block: &'a Block) {
+        for instruction in &block.instructions {
+            self.evaluate_instruction(instruction);
+        }
+    }
+
+    fn evaluate_instruction(&mut self, instruction: &'a Instruction) {
+        match &instruction.instruction_type {
+            InstructionType::Push(PushType::Int(i)) => self.stack.push(*i),
+            InstructionType::InfixOperators(op) => {
+                let right = self.pop(instruction);
+                let left = self.pop(instruction);
+                let Some(value) = op.evaluate(left, right) else {
+                    throw_exception_span(&instruction.span, "Division by zero or signed overflow of a division in constant expression".to_string());
+                    unreachable!();
+                };
+                self.stack.push(value);
+            }
+            InstructionType::Pop => {
+                self.pop(instruction);
+            }
+            InstructionType::Dup => {
+                let a = self.pop(instruction);
+                self.stack.extend([a, a]);
+            }
+            InstructionType::Over => {
+                let b = self.pop(instruction);
+                let a = self.pop(instruction);
+                self.stack.extend([a, b, a]);
+            }
+            InstructionType::Swap => {
+                let b = self.pop(instruction);
+                let a = self.pop(instruction);
+                self.stack.extend([b, a]);
+            }
+            InstructionType::Rot => {
+                let c = self.pop(instruction);
+                let b = self.pop(instruction);
+                let a = self.pop(instruction);
+                self.stack.extend([b, c, a]);
+            }
+            InstructionType::Pick => {
+                let n = self.pop(instruction);
+                if n < 0 || n as usize >= self.stack.len() {
+                    throw_exception_span(&instruction.span, format!("Can not pick item {} of a constant expression, the stack only holds {} items", n, self.stack.len()));
+                }
+                self.stack.push(self.stack[self.stack.len() - 1 - n as usize]);
+            }
+            InstructionType::MacroCall(call) if self.program.inlines.contains_key(&call.identifier) => {
+                let block = self.expansion.enter(&self.program.inlines[&call.identifier], &call.arguments, &instruction.span);
+                self.evaluate_block(block);
+                self.expansion.leave();
+            }
+            InstructionType::Argument(_, index) => {
+                let scope = self.expansion.enter_argument();
+                self.evaluate_block(&scope.arguments[*index]);
+                self.expansion.leave_argument(scope);
+            }
+            InstructionType::Identifier(identifier) if self.program.inlines.contains_key(identifier) => {
+                let block = self.expansion.enter_plain(&self.program.inlines[identifier], &instruction.span);
+                self.evaluate_block(block);
+                self.expansion.leave();
+            }
+            InstructionType::Identifier(identifier) | InstructionType::MacroCall(MacroCall { identifier, .. }) => {
+                throw_exception_span(&instruction.span, format!("'{}' is not a constant, only inlines defined before this point can be used in a constant expression", identifier));
+            }
+            other => {
+                throw_exception_span(&instruction.span, format!("\"{}\" is not allowed in a constant expression", other));
+            }
+        }
+    }
+
+    fn pop(&mut self, instruction: &Instruction) -> i64 {
+        let Some(value) = self.stack.pop() else {
+            throw_exception_span(&instruction.span, format!("\"{}\" needs more values than the constant expression provides", instruction.instruction_type));
+            unreachable!();
+        };
+        value
+    }
+}
@@ -0,0 +1,108 @@
+use crate::parser::{Block, Inline, MacroCall};
+use crate::program::Program;
+use crate::throw_exception_span;
+use crate::tokens::Span;
+
+/// The inlines a pass over the program is expanding. Every pass expands them the same way: an inline
+/// is replaced by its block, and an argument by the block passed for it, as if it was written at the
+/// call site. `A` holds the arguments of a call, borrowed from the program or owned.
+///
+/// The for loops of the procedure are tracked as well, so an `i` passed as an argument refers to
+/// the loop around the call and not to a loop of the inline.
+pub struct Expansion<A> {
+    /// Inlines being expanded, the innermost last
+    inlines: Vec<String>,
+    /// (arguments, length of `inlines`, for loops at the call) of the inlines being expanded
+    argument_frames: Vec<(A, usize, usize)>,
+    /// For loops being executed
+    loops: usize,
+    /// Positions of the for loops `i` can refer to, the innermost last
+    visible_loops: Vec<usize>,
+}
+
+/// An argument being expanded, returned by `Expansion::enter_argument`
+pub struct ArgumentScope<A> {
+    pub arguments: A,
+    depth: usize,
+    loops: usize,
+    /// Inlines expanded between the call site and the argument, which the argument can not see
+    inlines: Vec<String>,
+    /// Loops of those inlines, which the argument can not see either
+    hidden_loops: Vec<usize>,
+}
+
+impl<A: AsRef<[Block]> + Default> Expansion<A> {
+    pub fn new() -> Expansion<A> {
+        Expansion { inlines: Vec::new(), argument_frames: Vec::new(), loops: 0, visible_loops: Vec::new() }
+    }
+
+    /// Enters the expansion of an inline called with `arguments`, returns its block. Exits with an
+    /// error when the amount of arguments is wrong or the inline expands into itself.
+    pub fn enter<'p>(&mut self, inline: &'p Inline, arguments: A, span: &Span) -> &'p Block {
+        inline.check_arguments(span, arguments.as_ref().len());
+        if self.inlines.contains(&inline.identifier) {
+            throw_exception_span(span, format!("Inline '{}' expands into itself", inline.identifier));
+        }
+
+        self.argument_frames.push((arguments, self.inlines.len(), self.loops));
+        self.inlines.push(inline.identifier.clone());
+        &inline.block
+    }
+
+    /// Enters the expansion of an inline used without parentheses
+    pub fn enter_plain<'p>(&mut self, inline: &'p Inline, span: &Span) -> &'p Block {
+        self.enter(inline, A::default(), span)
+    }
+
+    pub fn leave(&mut self) {
+        self.inlines.pop();
+        self.argument_frames.pop();
+    }
+
+    /// Enters an argument of the innermost inline, its block is `arguments[index]` of the scope.
+    /// Until the scope is left with `leave_argument`, the inlines expanded and the loops entered since
+    /// the call are hidden.
+    pub fn enter_argument(&mut self) -> ArgumentScope<A> {
+        let (arguments, depth, loops) = self.argument_frames.pop().expect("Arguments are only used within an inline");
+        let inlines = self.inlines.split_off(depth);
+        let hidden_loops = self.visible_loops.split_off(self.visible_loops.partition_point(|position| *position < loops));
+        ArgumentScope { arguments, depth, loops, inlines, hidden_loops }
+    }
+
+    pub fn leave_argument(&mut self, scope: ArgumentScope<A>) {
+        self.inlines.extend(scope.inlines);
+        self.visible_loops.extend(scope.hidden_loops);
+        self.argument_frames.push((scope.arguments, scope.depth, scope.loops));
+    }
+
+    pub fn enter_loop(&mut self) {
+        self.visible_loops.push(self.loops);
+        self.loops += 1;
+    }
+
+    pub fn leave_loop(&mut self) {
+        self.visible_loops.pop();
+        self.loops -= 1;
+    }
+
+    /// How many loops the one `i` refers to is nested outside of the innermost loop, None when `i`
+    /// is not inside of a loop it can see
+    pub fn index_depth(&self) -> Option<usize> {
+        self.visible_loops.last().map(|position| self.loops - 1 - position)
+    }
+}
+
+impl<A: AsRef<[Block]> + Default> Default for Expansion<A> {
+    fn default() -> Expansion<A> {
+        Expansion::new()
+    }
+}
+
+/// The inline called by `name(a, b)`, exits with an error when the name is not an inline
+pub fn called_inline<'p>(program: &'p Program, call: &MacroCall, span: &Span) -> &'p Inline {
+    let Some(inline) = program.inlines.get(&call.identifier) else {
+        throw_exception_span(span, format!("'{}' is not an inline, only inlines take arguments", call.identifier));
+        unreachable!();
+    };
+    inline
+}
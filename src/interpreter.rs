@@ -0,0 +1,1018 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Read, Stdout, Write};
+use std::os::unix::fs::OpenOptionsExt;
+
+use crate::bytecode::Op;
+use crate::compiler::CompilerOptions;
+use crate::expansion::{self, Expansion};
+use crate::operators::InfixOperators;
+use crate::parser::{Block, DataValue, Instruction, InstructionType, Procedure, PushType};
+use crate::program::Program;
+use crate::throw_exception_span;
+use crate::tokens::Span;
+
+// Regions of the emulated address space: memories, data and the arguments are static, string
+// literals are read-only and the heap is grown with brk. Address 0 is never valid.
+const STATIC_BASE: i64 = 0x1000_0000;
+const STRINGS_BASE: i64 = 0x2000_0000;
+const HEAP_BASE: i64 = 0x4000_0000;
+/// Most bytes the static and string regions can hold, so they never overlap the next region
+pub(crate) const REGION_LIMIT: usize = 0x1000_0000;
+const HEAP_LIMIT: usize = 0x4000_0000;
+const PAGE_SIZE: usize = 4096;
+
+const BACKTRACE_LIMIT: usize = 64;
+
+/// Why execution stopped before the end of the program
+#[derive(Debug)]
+pub enum Stop {
+    /// The program called `exit` with this code
+    Exit(i64),
+    /// A runtime error, already reported on stderr
+    Error,
+}
+
+/// Why the machine could not execute an op, the interpreter and the VM report errors at their
+/// instruction
+#[derive(Debug)]
+pub(crate) enum Trap {
+    Exit(i64),
+    Error(String),
+}
+
+impl Trap {
+    /// `report` reports the error and returns its `Stop`
+    pub(crate) fn stop(self, report: impl FnOnce(&str) -> Stop) -> Stop {
+        match self {
+            Trap::Exit(code) => Stop::Exit(code),
+            Trap::Error(message) => report(&message),
+        }
+    }
+}
+
+impl From<String> for Trap {
+    fn from(message: String) -> Trap {
+        Trap::Error(message)
+    }
+}
+
+/// State of a running program that outlives a single execution: the data stack, memory and open files.
+/// The REPL keeps one around while the program grows.
+pub struct Machine {
+    pub stack: Vec<i64>,
+    pub(crate) options: CompilerOptions,
+    memory: Memory,
+    /// Addresses of the memories and data, allocated when they are first used
+    addresses: HashMap<String, i64>,
+    /// Addresses of the string literals
+    strings: HashMap<String, i64>,
+    /// Files opened by the program, by file descriptor
+    files: HashMap<i64, File>,
+    next_fd: i64,
+    out: BufWriter<Stdout>,
+}
+
+impl Machine {
+    /// `arguments` become `argv`, the first one is the name of the program
+    pub fn new(options: CompilerOptions, arguments: &[String]) -> Machine {
+        let mut memory = Memory::default();
+        let mut pointers: Vec<u8> = Vec::new();
+        for argument in arguments {
+            let address = memory.allocate(&[argument.as_bytes(), &[0]].concat()).expect("The arguments fit in memory");
+            pointers.extend(address.to_le_bytes());
+        }
+        pointers.extend(0i64.to_le_bytes()); // argv ends with a null pointer
+        let argv = memory.allocate(&pointers).expect("The arguments fit in memory");
+
+        let addresses = HashMap::from([
+            (String::from("argc"), memory.allocate(&(arguments.len() as i64).to_le_bytes()).expect("argc fits in memory")),
+            (String::from("argv"), memory.allocate(&argv.to_le_bytes()).expect("argv fits in memory")),
+        ]);
+
+        Machine {
+            stack: Vec::new(),
+            options,
+            memory,
+            addresses,
+            strings: HashMap::new(),
+            files: HashMap::new(),
+            next_fd: 3,
+            out: BufWriter::new(std::io::stdout()),
+        }
+    }
+
+    /// Prints a value for `put`
+    pub(crate) fn put(&mut self, value: i64) {
+        let _ = writeln!(self.out, "{}", value);
+    }
+
+    /// Writes what the program printed so far to stdout
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+
+    /// Prints `file:line:col: <message>` and a backtrace of (procedure, its span) to stderr, like a
+    /// compiled program does
+    pub(crate) fn report(&mut self, span: &Span, message: &str, backtrace: &[(&str, &Span)]) -> Stop {
+        self.flush();
+        eprintln!("{}:{}:{}: {}", span.file, span.line, span.column, message);
+        if backtrace.is_empty() {
+            return Stop::Error;
+        }
+        eprintln!("Backtrace (most recent call first):");
+        for (identifier, span) in backtrace.iter().take(BACKTRACE_LIMIT) {
+            eprintln!("\tat {} ({}:{}:{})", identifier, span.file, span.line, span.column);
+        }
+        if backtrace.len() > BACKTRACE_LIMIT {
+            eprintln!("\t...");
+        }
+        Stop::Error
+    }
+
+    pub(crate) fn pop(&mut self) -> Result<i64, String> {
+        self.stack.pop().ok_or_else(|| String::from("data stack underflow"))
+    }
+
+    pub(crate) fn push(&mut self, value: i64) -> Result<(), String> {
+        if self.stack.len() >= self.options.data_stack_limit() {
+            return Err(String::from("data stack overflow"));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    /// Executes an op that only works on the data stack, memory and syscalls, see `Op::primitive`.
+    /// Control flow is left to the interpreter and the VM.
+    pub(crate) fn step(&mut self, op: &Op) -> Result<(), Trap> {
+        match op {
+            Op::Put => {
+                let value = self.pop()?;
+                self.put(value);
+            }
+            Op::Push(value) => self.push(*value)?,
+            Op::Infix(op) => {
+                let right = self.pop()?;
+                let left = self.pop()?;
+                let value = infix(op, left, right, self.options.checked_arith)?;
+                self.push(value)?;
+            }
+            Op::Pop => _ = self.pop()?,
+            Op::Dup => {
+                let a = self.pop()?;
+                self.push(a)?;
+                self.push(a)?;
+            }
+            Op::Over => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(a)?;
+                self.push(b)?;
+                self.push(a)?;
+            }
+            Op::Pick => {
+                let index = self.pop()?;
+                let stack = &self.stack;
+                let Some(&value) = usize::try_from(index).ok().filter(|i| *i < stack.len()).map(|i| &stack[stack.len() - 1 - i]) else {
+                    return Err(Trap::Error(String::from("data stack underflow, pick index is out of bounds")));
+                };
+                self.push(value)?;
+            }
+            Op::Swap => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b)?;
+                self.push(a)?;
+            }
+            Op::Rot => {
+                // ( a b c -- b c a )
+                let c = self.pop()?;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                self.push(b)?;
+                self.push(c)?;
+                self.push(a)?;
+            }
+            Op::Size => self.push(self.stack.len() as i64)?,
+            Op::Load(width) => {
+                let address = self.pop()?;
+                let value = self.load(address, *width)?;
+                self.push(value)?;
+            }
+            Op::Store(width) => {
+                let value = self.pop()?;
+                let address = self.pop()?;
+                self.store(address, *width, value)?;
+            }
+            Op::Syscall(count) => {
+                // Popped in the same order as the compiled code: rax, rdi, rsi, rdx, r10, r9, r8
+                let mut registers = [-1i64; 7];
+                for register in registers.iter_mut().take(*count as usize) {
+                    *register = self.pop()?;
+                }
+                let result = self.syscall(registers)?;
+                self.push(result)?;
+            }
+            _ => unreachable!("Control flow is executed by the interpreter and the VM"),
+        }
+        Ok(())
+    }
+
+    pub(crate) fn load(&self, address: i64, width: usize) -> Result<i64, String> {
+        let mut bytes = [0; 8];
+        match self.memory.bytes(address, width) {
+            Some(value) => bytes[..width].copy_from_slice(value),
+            None => return Err(format!("invalid memory access, can not load from {:#x}", address)),
+        }
+        Ok(i64::from_le_bytes(bytes))
+    }
+
+    pub(crate) fn store(&mut self, address: i64, width: usize, value: i64) -> Result<(), String> {
+        match self.memory.bytes_mut(address, width) {
+            Ok(bytes) => bytes.copy_from_slice(&value.to_le_bytes()[..width]),
+            Err(Fault::ReadOnly) => return Err(String::from("attempted to write into a string literal")),
+            Err(Fault::Invalid) => return Err(format!("invalid memory access, can not store at {:#x}", address)),
+        }
+        Ok(())
+    }
+
+    /// Address of a string literal, with a null terminator like in compiled programs
+    pub(crate) fn string(&mut self, str: &str) -> Result<i64, String> {
+        if let Some(address) = self.strings.get(str) {
+            return Ok(*address);
+        }
+
+        let strings = &mut self.memory.strings;
+        if strings.len() + str.len() + 1 > REGION_LIMIT {
+            return Err(String::from("out of memory, too many string literals"));
+        }
+        let address = STRINGS_BASE + strings.len() as i64;
+        strings.extend(str.bytes().chain([0]));
+        self.strings.insert(str.to_string(), address);
+        Ok(address)
+    }
+
+    /// Address of the memory `argc` or `argv`, which are set up with the arguments of the program
+    pub(crate) fn argument_address(&self, identifier: &str) -> i64 {
+        self.addresses[identifier]
+    }
+
+    /// Drops the address of a memory or data, which is allocated again when it is used next
+    pub(crate) fn forget(&mut self, identifier: &str) {
+        self.addresses.remove(identifier);
+    }
+
+    pub(crate) fn allocate_memory(&mut self, size: usize) -> Result<i64, String> {
+        self.memory.allocate(&vec![0; size]).ok_or_else(|| String::from("out of memory, the static memory is full"))
+    }
+
+    /// Allocates the values of a data the same way the compiler lays them out
+    pub(crate) fn allocate_data(&mut self, width: usize, values: &[DataValue]) -> Result<i64, String> {
+        let mut bytes = Vec::new();
+        for value in values {
+            match value {
+                DataValue::Int(i) => bytes.extend(&i.to_le_bytes()[..width]),
+                // Bytes are stored inline, wider data stores the string the same way it is pushed
+                DataValue::Str(str, _) if width == 1 => bytes.extend(str.bytes().chain([0])),
+                DataValue::Str(str, _) => {
+                    bytes.extend((str.len() as i64).to_le_bytes());
+                    bytes.extend(self.string(str)?.to_le_bytes());
+                }
+            }
+        }
+        self.memory.allocate(&bytes).ok_or_else(|| String::from("out of memory, the static memory is full"))
+    }
+
+    /// Emulates the Linux syscalls read, write, open, close, brk and exit. Others fail with ENOSYS.
+    /// `registers` are rax, rdi, rsi, rdx, r10, r9 and r8, as they are popped by a syscall
+    fn syscall(&mut self, registers: [i64; 7]) -> Result<i64, Trap> {
+        let [number, rdi, rsi, rdx, _, _, _] = registers;
+        if matches!(number, 0 | 1) && rdx < 0 {
+            return Ok(-EFAULT);
+        }
+
+        let result = match number {
+            0 => {
+                self.flush(); // Prompts are shown before waiting for input
+                let Ok(buffer) = self.memory.bytes_mut(rsi, rdx as usize) else {
+                    return Ok(-EFAULT);
+                };
+                let read = match rdi {
+                    0 => std::io::stdin().read(buffer),
+                    fd => match self.files.get_mut(&fd) {
+                        Some(file) => file.read(buffer),
+                        None => return Ok(-EBADF),
+                    },
+                };
+                read.map_or_else(errno, |read| read as i64)
+            }
+            1 => {
+                let Some(bytes) = self.memory.bytes(rsi, rdx as usize) else {
+                    return Ok(-EFAULT);
+                };
+                let written = match rdi {
+                    1 => self.out.write_all(bytes),
+                    2 => {
+                        let _ = self.out.flush();
+                        std::io::stderr().write_all(bytes)
+                    }
+                    fd => match self.files.get_mut(&fd) {
+                        Some(file) => file.write_all(bytes),
+                        None => return Ok(-EBADF),
+                    },
+                };
+                written.map_or_else(errno, |_| rdx)
+            }
+            2 => {
+                let Some(path) = self.memory.c_string(rdi) else {
+                    return Ok(-EFAULT);
+                };
+                let path = String::from_utf8_lossy(path).to_string();
+                let file = OpenOptions::new()
+                    .read(rsi & 3 != 1)
+                    .write(rsi & 3 != 0)
+                    .create(rsi & O_CREAT != 0)
+                    .truncate(rsi & O_TRUNC != 0)
+                    .append(rsi & O_APPEND != 0)
+                    .mode(rdx as u32)
+                    .open(path);
+                match file {
+                    Ok(file) => {
+                        let fd = self.next_fd;
+                        self.next_fd += 1;
+                        self.files.insert(fd, file);
+                        fd
+                    }
+                    Err(error) => errno(error),
+                }
+            }
+            3 => match rdi {
+                0..=2 => 0,
+                fd => self.files.remove(&fd).map_or(-EBADF, |_| 0),
+            },
+            12 => {
+                let memory = &mut self.memory;
+                if (HEAP_BASE..=HEAP_BASE + HEAP_LIMIT as i64).contains(&rdi) {
+                    // Linux maps whole pages, so the bytes right after the break stay accessible
+                    memory.program_break = (rdi - HEAP_BASE) as usize;
+                    memory.heap.resize(memory.program_break.next_multiple_of(PAGE_SIZE), 0);
+                }
+                HEAP_BASE + memory.program_break as i64
+            }
+            60 | 231 => return Err(Trap::Exit(rdi)),
+            _ => -ENOSYS,
+        };
+        Ok(result)
+    }
+}
+
+/// Runs `main`, returns the exit code of the program
+pub fn run(program: &Program, machine: &mut Machine) -> i32 {
+    let main = program.procedures.get("main").expect("The parser requires a main procedure");
+    let result = Interpreter::new(program, machine).call_main(main);
+    machine.flush();
+
+    match result {
+        Ok(()) => 0,
+        Err(Stop::Exit(code)) => code as i32,
+        Err(Stop::Error) => 1,
+    }
+}
+
+/// Executes a block outside of any procedure, like a line typed into the REPL
+pub fn execute(program: &Program, machine: &mut Machine, block: &Block) -> Result<(), Stop> {
+    let result = match Interpreter::new(program, machine).exec_block(block)? {
+        Flow::Next => Ok(()),
+        _ => {
+            eprintln!("Error: 'return' can only be used inside of a procedure");
+            Err(Stop::Error)
+        }
+    };
+    machine.flush();
+    result
+}
+
+/// Rejects what the interpreter can not execute before anything runs, like the compiler does: words
+/// that are not known, `extern` procedures and `asm` blocks in the procedures and in `input`, a
+/// block executed outside of any procedure. Exits with an error at the first one.
+pub fn resolve(program: &Program, input: Option<&Block>) {
+    let mut resolver = Resolver { program, expansion: Expansion::new() };
+    for procedure in program.procedures.values() {
+        resolver.resolve_block(&procedure.block);
+    }
+    if let Some(block) = input {
+        resolver.resolve_block(block);
+    }
+}
+
+struct Resolver<'a> {
+    program: &'a Program,
+    expansion: Expansion<&'a [Block]>,
+}
+
+impl<'a> Resolver<'a> {
+    fn resolve_block(&mut self, block: &'a Block) {
+        for instruction in &block.instructions {
+            self.resolve_instruction(instruction);
+        }
+    }
+
+    fn resolve_instruction(&mut self, instruction: &'a Instruction) {
+        let span = &instruction.span;
+        match &instruction.instruction_type {
+            InstructionType::While(whl) => {
+                self.resolve_block(&whl.condition);
+                self.resolve_block(&whl.block);
+            }
+            InstructionType::For(fr) => {
+                self.resolve_block(&fr.range);
+                self.resolve_block(&fr.block);
+            }
+            InstructionType::Let(lt) => self.resolve_block(&lt.block),
+            InstructionType::If(iff) => {
+                for (condition, body) in std::iter::once(&iff.if_block).chain(&iff.elif_blocks) {
+                    self.resolve_block(condition);
+                    self.resolve_block(body);
+                }
+                if let Some(else_block) = &iff.else_block {
+                    self.resolve_block(else_block);
+                }
+            }
+            InstructionType::Match(mtch) => {
+                self.resolve_block(&mtch.value);
+                for (_, block) in &mtch.cases {
+                    self.resolve_block(block);
+                }
+                if let Some(else_block) = &mtch.else_block {
+                    self.resolve_block(else_block);
+                }
+            }
+            InstructionType::MacroCall(call) => {
+                let inline = expansion::called_inline(self.program, call, span);
+                let block = self.expansion.enter(inline, &call.arguments, span);
+                self.resolve_block(block);
+                self.expansion.leave();
+            }
+            InstructionType::Argument(_, index) => {
+                let scope = self.expansion.enter_argument();
+                self.resolve_block(&scope.arguments[*index]);
+                self.expansion.leave_argument(scope);
+            }
+            InstructionType::Asm(_) => {
+                throw_exception_span(span, String::from("asm blocks can only be compiled with the NASM backend, they can not be interpreted"));
+            }
+            InstructionType::Identifier(identifier) => {
+                let program = self.program;
+                if let Some(inline) = program.inlines.get(identifier) {
+                    let block = self.expansion.enter_plain(inline, span);
+                    self.resolve_block(block);
+                    self.expansion.leave();
+                } else if program.externs.contains_key(identifier) {
+                    throw_exception_span(span, format!("'{}' is a C function, extern procedures can not be interpreted", identifier));
+                } else if !program.procedures.contains_key(identifier) && !program.memories.contains_key(identifier) && !program.datas.contains_key(identifier) {
+                    throw_exception_span(span, format!("Word '{}' is not known", identifier));
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Runs `f` on a thread with a stack deep enough for the nesting of procedure calls the options
+/// allow, as every call of a Stapel procedure recurses in the interpreter
+pub fn on_large_stack<T: Send + 'static>(options: &CompilerOptions, f: impl FnOnce() -> T + Send + 'static) -> T {
+    let size = (options.call_limit() * 16 * 1024).clamp(64 << 20, 1 << 30);
+    let thread = std::thread::Builder::new().stack_size(size).spawn(f).expect("Could not start the interpreter");
+    thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
+
+/// How control continues after an instruction
+enum Flow<'a> {
+    Next,
+    Break,
+    Continue,
+    Return,
+    /// Return, after which the caller calls this procedure
+    TailCall(&'a Procedure),
+}
+
+struct Interpreter<'a> {
+    program: &'a Program,
+    machine: &'a mut Machine,
+    /// Procedures being executed, the innermost last
+    calls: Vec<&'a Procedure>,
+    /// (binding id, value) of the lets in scope
+    bindings: Vec<(usize, i64)>,
+    /// Index of the for loops around the instruction being executed
+    indices: Vec<i64>,
+    expansion: Expansion<&'a [Block]>,
+}
+
+impl<'a> Interpreter<'a> {
+    fn new(program: &'a Program, machine: &'a mut Machine) -> Interpreter<'a> {
+        Interpreter { program, machine, calls: Vec::new(), bindings: Vec::new(), indices: Vec::new(), expansion: Expansion::new() }
+    }
+
+    fn call_main(&mut self, main: &'a Procedure) -> Result<(), Stop> {
+        self.calls.push(main);
+        let flow = self.exec_block(&main.block)?;
+        self.calls.pop();
+
+        // main has nothing to return to, like a compiled program returning with an empty return stack
+        match flow {
+            Flow::Next => return Ok(()),
+            Flow::TailCall(procedure) => self.call(procedure, &main.span)?,
+            _ => (),
+        }
+        self.calls.push(main);
+        Err(self.error(&main.span, "'main' returned, there is no procedure to return to, end the program with exit instead"))
+    }
+
+    /// Calls a procedure, and the procedures it tail calls
+    fn call(&mut self, mut procedure: &'a Procedure, span: &Span) -> Result<(), Stop> {
+        loop {
+            let limit = self.machine.options.call_limit();
+            if self.calls.len() >= limit {
+                return Err(self.error(span, &format!("return stack overflow, procedure calls are nested more than {} deep", limit)));
+            }
+            let (bindings, indices) = (self.bindings.len(), self.indices.len());
+
+            // Inlines and loops of the caller are not visible in the procedure
+            let expansion = std::mem::take(&mut self.expansion);
+            self.calls.push(procedure);
+            let flow = self.exec_block(&procedure.block)?;
+            self.calls.pop();
+            self.expansion = expansion;
+            self.bindings.truncate(bindings);
+            self.indices.truncate(indices);
+
+            match flow {
+                Flow::TailCall(next) => procedure = next,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn exec_block(&mut self, block: &'a Block) -> Result<Flow<'a>, Stop> {
+        for (index, instruction) in block.instructions.iter().enumerate() {
+            let tail = !self.machine.options.no_tco && block.instructions.get(index + 1).is_some_and(|next| next.instruction_type == InstructionType::Return);
+            match self.exec_instruction(instruction, tail)? {
+                Flow::Next => (),
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Executes one instruction, `tail` when it is followed by a `return`
+    fn exec_instruction(&mut self, instruction: &'a Instruction, tail: bool) -> Result<Flow<'a>, Stop> {
+        let span = &instruction.span;
+        if let Some(op) = Op::primitive(&instruction.instruction_type) {
+            self.machine.step(&op).map_err(|trap| trap.stop(|message| self.error(span, message)))?;
+            return Ok(Flow::Next);
+        }
+        match &instruction.instruction_type {
+            InstructionType::Push(PushType::Str(str, _)) => {
+                let address = self.string(span, str)?;
+                self.push(span, str.len() as i64)?;
+                self.push(span, address)?;
+            }
+            InstructionType::While(whl) => loop {
+                match self.exec_block(&whl.condition)? {
+                    Flow::Next => (),
+                    flow => return Ok(flow),
+                }
+                if self.pop(span)? == 0 {
+                    break;
+                }
+                match self.exec_block(&whl.block)? {
+                    Flow::Next | Flow::Continue => (),
+                    Flow::Break => break,
+                    flow => return Ok(flow),
+                }
+            },
+            InstructionType::For(fr) => {
+                match self.exec_block(&fr.range)? {
+                    Flow::Next => (),
+                    flow => return Ok(flow),
+                }
+                let end = self.pop(span)?;
+                let start = self.pop(span)?;
+
+                self.indices.push(start);
+                self.expansion.enter_loop();
+                while *self.indices.last().unwrap() < end {
+                    match self.exec_block(&fr.block)? {
+                        Flow::Next | Flow::Continue => (),
+                        Flow::Break => break,
+                        flow => {
+                            self.indices.pop();
+                            self.expansion.leave_loop();
+                            return Ok(flow);
+                        }
+                    }
+                    *self.indices.last_mut().unwrap() += 1;
+                }
+                self.indices.pop();
+                self.expansion.leave_loop();
+            }
+            InstructionType::Index => {
+                let Some(depth) = self.expansion.index_depth() else {
+                    return Err(self.error(span, "'i' can only be used inside of a for loop"));
+                };
+                let index = self.indices[self.indices.len() - 1 - depth];
+                self.push(span, index)?;
+            }
+            InstructionType::Let(lt) => {
+                // The first binding takes the deepest value
+                let start = self.bindings.len();
+                for binding in lt.bindings.iter().rev() {
+                    let value = self.pop(span)?;
+                    self.bindings.insert(start, (binding.id, value));
+                }
+                let flow = self.exec_block(&lt.block)?;
+                self.bindings.truncate(start);
+                return Ok(flow);
+            }
+            InstructionType::Binding(binding) => {
+                let Some(&(_, value)) = self.bindings.iter().rev().find(|(id, _)| *id == binding.id) else {
+                    return Err(self.error(span, &format!("'{}' is not bound here", binding.identifier)));
+                };
+                self.push(span, value)?;
+            }
+            InstructionType::If(iff) => {
+                let branches = std::iter::once(&iff.if_block).chain(&iff.elif_blocks);
+                for (condition, body) in branches {
+                    match self.exec_block(condition)? {
+                        Flow::Next => (),
+                        flow => return Ok(flow),
+                    }
+                    if self.pop(span)? != 0 {
+                        return self.exec_block(body);
+                    }
+                }
+                if let Some(else_block) = &iff.else_block {
+                    return self.exec_block(else_block);
+                }
+            }
+            InstructionType::Match(mtch) => {
+                match self.exec_block(&mtch.value)? {
+                    Flow::Next => (),
+                    flow => return Ok(flow),
+                }
+                let value = self.pop(span)?;
+                if let Some((_, block)) = mtch.cases.iter().find(|(case, _)| *case == value) {
+                    return self.exec_block(block);
+                }
+                if let Some(else_block) = &mtch.else_block {
+                    return self.exec_block(else_block);
+                }
+            }
+            InstructionType::MacroCall(call) => {
+                let inline = expansion::called_inline(self.program, call, span);
+                let block = self.expansion.enter(inline, &call.arguments, span);
+                let flow = self.exec_block(block);
+                self.expansion.leave();
+                return flow;
+            }
+            InstructionType::Argument(_, index) => {
+                let scope = self.expansion.enter_argument();
+                let flow = self.exec_block(&scope.arguments[*index]);
+                self.expansion.leave_argument(scope);
+                return flow;
+            }
+            InstructionType::Assert => {
+                if self.pop(span)? == 0 && !self.machine.options.release {
+                    return Err(self.error(span, "assertion failed"));
+                }
+            }
+            InstructionType::Panic(message) => {
+                if !self.machine.options.release {
+                    return Err(self.error(span, message));
+                }
+            }
+            InstructionType::Asm(_) => unreachable!("resolve rejects asm blocks"),
+            InstructionType::Identifier(identifier) => {
+                if let Some(procedure) = self.program.procedures.get(identifier) {
+                    if tail {
+                        return Ok(Flow::TailCall(procedure));
+                    }
+                    self.call(procedure, span)?;
+                } else if let Some(inline) = self.program.inlines.get(identifier) {
+                    let block = self.expansion.enter_plain(inline, span);
+                    let flow = self.exec_block(block);
+                    self.expansion.leave();
+                    return flow;
+                } else if self.program.memories.contains_key(identifier) || self.program.datas.contains_key(identifier) {
+                    let address = self.address_of(span, identifier)?;
+                    self.push(span, address)?;
+                } else {
+                    unreachable!("resolve rejects externs and words that are not known");
+                }
+            }
+            InstructionType::Return => return Ok(Flow::Return),
+            InstructionType::Break => return Ok(Flow::Break),
+            InstructionType::Continue => return Ok(Flow::Continue),
+            _ => unreachable!("Primitives are executed by Machine::step"),
+        }
+        Ok(Flow::Next)
+    }
+
+    /// Address of a memory or data, which is allocated and initialized when it is first used
+    fn address_of(&mut self, span: &Span, identifier: &String) -> Result<i64, Stop> {
+        if let Some(address) = self.machine.addresses.get(identifier) {
+            return Ok(*address);
+        }
+
+        let allocation = match (self.program.memories.get(identifier), self.program.datas.get(identifier)) {
+            (Some(memory), _) => self.machine.allocate_memory(memory.size),
+            (_, Some(data)) => self.machine.allocate_data(data.width, &data.values),
+            _ => unreachable!("Only memories and data have an address"),
+        };
+        let address = allocation.map_err(|message| self.error(span, &message))?;
+        self.machine.addresses.insert(identifier.clone(), address);
+        Ok(address)
+    }
+
+    fn string(&mut self, span: &Span, str: &str) -> Result<i64, Stop> {
+        self.machine.string(str).map_err(|message| self.error(span, &message))
+    }
+
+    fn pop(&mut self, span: &Span) -> Result<i64, Stop> {
+        self.machine.pop().map_err(|message| self.error(span, &message))
+    }
+
+    fn push(&mut self, span: &Span, value: i64) -> Result<(), Stop> {
+        self.machine.push(value).map_err(|message| self.error(span, &message))
+    }
+
+    fn error(&mut self, span: &Span, message: &str) -> Stop {
+        let backtrace: Vec<(&str, &Span)> = self.calls.iter().rev().map(|procedure| (procedure.identifier.as_str(), &procedure.span)).collect();
+        self.machine.report(span, message, &backtrace)
+    }
+}
+
+const EBADF: i64 = 9;
+const EFAULT: i64 = 14;
+const ENOSYS: i64 = 38;
+const O_CREAT: i64 = 0o100;
+const O_TRUNC: i64 = 0o1000;
+const O_APPEND: i64 = 0o2000;
+
+/// Applies an infix operator, the error message on division by zero and, with `checked_arith`, overflow
+pub(crate) fn infix(op: &InfixOperators, left: i64, right: i64, checked_arith: bool) -> Result<i64, String> {
+    let value = match op {
+        InfixOperators::Plus if checked_arith => left.checked_add(right),
+        InfixOperators::Minus if checked_arith => left.checked_sub(right),
+        InfixOperators::Multiply if checked_arith => left.checked_mul(right),
+        _ => op.evaluate(left, right),
+    };
+    value.ok_or_else(|| match (op, right) {
+        (InfixOperators::Divide | InfixOperators::Modulo, 0) => String::from("division by zero"),
+        _ => format!("signed overflow in '{}'", op.symbol()),
+    })
+}
+
+/// Syscalls return errors as `-errno`
+fn errno(error: std::io::Error) -> i64 {
+    -(error.raw_os_error().unwrap_or(1) as i64)
+}
+
+enum Fault {
+    Invalid,
+    ReadOnly,
+}
+
+/// Byte addressable memory of the interpreted program, see `STATIC_BASE`
+#[derive(Default)]
+struct Memory {
+    statics: Vec<u8>,
+    /// Bytes of `statics` that are allocated, the rest of the last page is unused
+    statics_used: usize,
+    strings: Vec<u8>,
+    heap: Vec<u8>,
+    /// End of the heap as set with brk, `heap` is rounded up to whole pages
+    program_break: usize,
+}
+
+impl Memory {
+    /// The bytes of the region containing `address`, from that address on
+    fn tail(&self, address: i64) -> Option<&[u8]> {
+        let (region, base) = [(&self.statics, STATIC_BASE), (&self.strings, STRINGS_BASE), (&self.heap, HEAP_BASE)]
+            .into_iter()
+            .find(|(region, base)| address >= *base && address < base + region.len() as i64)?;
+        Some(&region[(address - base) as usize..])
+    }
+
+    fn bytes(&self, address: i64, length: usize) -> Option<&[u8]> {
+        self.tail(address)?.get(..length)
+    }
+
+    fn bytes_mut(&mut self, address: i64, length: usize) -> Result<&mut [u8], Fault> {
+        if self.bytes(address, 1).is_some() && (STRINGS_BASE..HEAP_BASE).contains(&address) {
+            return Err(Fault::ReadOnly);
+        }
+        let (region, base) = match address >= HEAP_BASE {
+            true => (&mut self.heap, HEAP_BASE),
+            false => (&mut self.statics, STATIC_BASE),
+        };
+        let offset = usize::try_from(address - base).map_err(|_| Fault::Invalid)?;
+        region.get_mut(offset..offset + length).ok_or(Fault::Invalid)
+    }
+
+    /// The bytes of the null terminated string at `address`, without the terminator
+    fn c_string(&self, address: i64) -> Option<&[u8]> {
+        let tail = self.tail(address)?;
+        tail.iter().position(|b| *b == 0).map(|end| &tail[..end])
+    }
+
+    /// Copies the bytes into the static region, aligned to 8 bytes. None when the region is full.
+    fn allocate(&mut self, bytes: &[u8]) -> Option<i64> {
+        let start = self.statics_used.next_multiple_of(8);
+        let end = start + bytes.len();
+        if end > REGION_LIMIT {
+            return None;
+        }
+        // Like the .bss of a compiled program the region is mapped in whole pages, so small overruns
+        // past the last memory do not fault
+        self.statics.resize(end.next_multiple_of(PAGE_SIZE), 0);
+        self.statics[start..end].copy_from_slice(bytes);
+        self.statics_used = end;
+        Some(STATIC_BASE + start as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::{checker, preprocessor};
+
+    /// Runs the source like `stapel run`, returns the exit code and the values left on the stack
+    fn run_source(source: &str) -> (i32, Vec<i64>) {
+        crate::recover_from_errors();
+        let options = CompilerOptions::default();
+        let mut l = Lexer::new(source.to_string(), String::from("test.spl"));
+        l.tokenize();
+        let mut p = Parser::new(preprocessor::process(l.tokens, &preprocessor::defines(&options)));
+        p.parse();
+        checker::check(&p.program, &options);
+        resolve(&p.program, None);
+
+        let mut machine = Machine::new(options, &[String::from("test")]);
+        let code = run(&p.program, &mut machine);
+        (code, machine.stack)
+    }
+
+    #[test]
+    fn while_break_continue() {
+        let source = "
+            proc main do
+                0 0 while dup 10 < do
+                    1 +
+                    if dup 3 = do continue end
+                    if dup 6 = do break end
+                    swap over + swap
+                end
+            end";
+        assert_eq!(run_source(source), (0, vec![12, 6]));
+    }
+
+    #[test]
+    fn for_and_let_unwind_on_return() {
+        let source = "
+            proc first_over do
+                let limit in
+                    for 0 100 do
+                        if i limit > do i return end
+                    end
+                end
+                -1
+            end
+
+            proc main do
+                for 0 3 do
+                    i first_over i 10 * +
+                end
+                5 let x in
+                    for 0 2 do 0 first_over pop end
+                    x
+                end
+            end";
+        assert_eq!(run_source(source), (0, vec![1, 12, 23, 5]));
+    }
+
+    #[test]
+    fn arguments_see_the_loops_of_the_call_site() {
+        let source = "
+            inline loopy(body) for 0 2 do body end end
+
+            proc main do
+                for 10 12 do loopy(i) end
+                for 5 6 do loopy(for 100 101 do i end i) end
+            end";
+        assert_eq!(run_source(source), (0, vec![10, 10, 11, 11, 100, 5, 100, 5]));
+    }
+
+    #[test]
+    fn tail_calls_do_not_nest() {
+        let source = "
+            proc count do
+                if dup 0 = do return end
+                1 - count return
+            end
+
+            proc main do 5000 count end";
+        assert_eq!(run_source(source), (0, vec![0]));
+    }
+
+    #[test]
+    fn match_cases() {
+        let source = "
+            proc name do
+                match
+                    case 1 do 10
+                    case 2 do 20
+                    case 3 4 + do 70
+                    else -1
+                end
+            end
+
+            proc main do 1 name 7 name 5 name end";
+        assert_eq!(run_source(source), (0, vec![10, 70, -1]));
+    }
+
+    #[test]
+    fn signed_division_truncates() {
+        let source = "proc main do -7 2 / -7 2 % 7 -2 / 7 -2 % end";
+        assert_eq!(run_source(source), (0, vec![-3, -1, -3, 1]));
+        assert_eq!(run_source("proc main do 1 0 / end").0, 1);
+    }
+
+    #[test]
+    fn syscalls_write_files() {
+        let path = std::env::temp_dir().join(format!("stapel_interpreter_{}", std::process::id()));
+        let source = format!("
+            memory fd 8 end
+
+            proc main do
+                420 577 \"{}\" swap pop 2 syscall4
+                fd swap @8
+                \"hello\" fd !8 1 syscall4
+                fd !8 3 syscall2
+                fd !8 3 syscall2
+            end", path.display());
+        let result = run_source(&source);
+        let written = std::fs::read_to_string(&path);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(result, (0, vec![5, 0, -EBADF]));
+        assert_eq!(written.unwrap(), "hello");
+    }
+
+    /// The buffer is checked before reading, a huge count can not allocate memory
+    #[test]
+    fn syscalls_read_into_memory() {
+        let path = std::env::temp_dir().join(format!("stapel_interpreter_read_{}", std::process::id()));
+        std::fs::write(&path, "hello").unwrap();
+        let source = format!("
+            memory fd 8 end
+            memory buffer 8 end
+
+            proc main do
+                0 0 \"{}\" swap pop 2 syscall4
+                fd swap @8
+                1000000000000 buffer fd !8 0 syscall4
+                8 buffer fd !8 0 syscall4
+                buffer !1 buffer 4 + !1
+                8 \"abc\" swap pop fd !8 0 syscall4
+            end", path.display());
+        let result = run_source(&source);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(result, (0, vec![-EFAULT, 5, 104, 111, -EFAULT]));
+    }
+
+    #[test]
+    fn syscalls_brk_and_exit() {
+        let source = "
+            proc main do
+                0 12 syscall2
+                dup 16 + 12 syscall2
+                dup 8 - 42 @8
+                8 - !8
+                3 60 syscall2
+            end";
+        assert_eq!(run_source(source), (3, vec![HEAP_BASE, 42]));
+    }
+
+    #[test]
+    fn string_literals_are_read_only() {
+        assert_eq!(run_source("proc main do \"abc\" swap pop 65 @1 end").0, 1);
+    }
+
+    #[test]
+    fn unknown_words_are_rejected_before_running() {
+        let source = "proc main do 1 put if 0 do foo end end";
+        assert!(std::panic::catch_unwind(|| run_source(source)).is_err());
+    }
+}
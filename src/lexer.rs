@@ -21,7 +21,7 @@ impl Lexer {
         // base.append(&mut input);
 
         Lexer {
-            current_char: input.get(0).copied(),
+            current_char: input.first().copied(),
             peek_char: input.get(1).copied(),
             input_chars: input,
             file_name,
@@ -95,12 +95,21 @@ impl Lexer {
                         throw_exception_span(&span, format!("'{}' is not a supported bit amount", num));
                     }
                 }
+                '(' | ')' | ',' => {
+                    let token = match c {
+                        '(' => TokenType::OpenParen,
+                        ')' => TokenType::CloseParen,
+                        _ => TokenType::Comma,
+                    };
+                    self.tokens.push(Token::new(token, span));
+                    self.next_character();
+                }
                 '=' => {
                     self.tokens.push(Token::new(TokenType::InfixOperators(InfixOperators::new(c.to_string())), span));
                     self.next_character();
                 }
                 '+' | '-' | '*' | '/' | '%' => {
-                    if c == '-' && self.peek_char.map_or(false, |p| p.is_numeric()) {
+                    if c == '-' && self.peek_char.is_some_and(|p| p.is_numeric()) {
                         let num = self.parse_num();
                         self.tokens.push(Token::new(TokenType::PushInt(num), span));
                     } else {
@@ -137,7 +146,8 @@ impl Lexer {
         let col = self.column;
         let mut word = String::new();
         
-        while self.current_char.is_some() && !self.current_char.unwrap().is_whitespace() {
+        // Parentheses and commas delimit the arguments of an inline, e.g. `name(a, b)`
+        while self.current_char.is_some_and(|c| !c.is_whitespace() && !['(', ')', ','].contains(&c)) {
             word.push(self.current_char.unwrap());
             self.next_character();
         }
@@ -158,16 +168,37 @@ impl Lexer {
             "end" => self.tokens.push(Token::new(TokenType::End, span)),
             "do" => self.tokens.push(Token::new(TokenType::Do, span)),
             "while" => self.tokens.push(Token::new(TokenType::While, span)),
+            "for" => self.tokens.push(Token::new(TokenType::For, span)),
+            "i" => self.tokens.push(Token::new(TokenType::Index, span)),
+            "let" => self.tokens.push(Token::new(TokenType::Let, span)),
+            "in" => self.tokens.push(Token::new(TokenType::In, span)),
+            "match" => self.tokens.push(Token::new(TokenType::Match, span)),
+            "case" => self.tokens.push(Token::new(TokenType::Case, span)),
+            "assert" => self.tokens.push(Token::new(TokenType::Assert, span)),
+            "asm" => self.tokens.push(Token::new(TokenType::Asm, span)),
+            "panic" => self.tokens.push(Token::new(TokenType::Panic, span)),
+            "static_assert" => self.tokens.push(Token::new(TokenType::StaticAssert, span)),
+            "#if" => self.tokens.push(Token::new(TokenType::DirectiveIf, span)),
+            "#elif" => self.tokens.push(Token::new(TokenType::DirectiveElif, span)),
+            "#else" => self.tokens.push(Token::new(TokenType::DirectiveElse, span)),
+            "#end" => self.tokens.push(Token::new(TokenType::DirectiveEnd, span)),
             "dup" => self.tokens.push(Token::new(TokenType::Dup, span)),
             "size" => self.tokens.push(Token::new(TokenType::Size, span)),
             "memory" => self.tokens.push(Token::new(TokenType::Memory, span)),
             "return" => self.tokens.push(Token::new(TokenType::Return, span)),
             "proc" => self.tokens.push(Token::new(TokenType::Procedure, span)),
             "inline" => self.tokens.push(Token::new(TokenType::Inline, span)),
+            "data" => self.tokens.push(Token::new(TokenType::Data, span)),
+            "struct" => self.tokens.push(Token::new(TokenType::Struct, span)),
+            "enum" => self.tokens.push(Token::new(TokenType::Enum, span)),
+            "extern" => self.tokens.push(Token::new(TokenType::Extern, span)),
+            "export" => self.tokens.push(Token::new(TokenType::Export, span)),
+            "break" => self.tokens.push(Token::new(TokenType::Break, span)),
+            "continue" => self.tokens.push(Token::new(TokenType::Continue, span)),
             _ => {
                 if word.starts_with("syscall") && word.len() == 8 {
                     let last_char = word.chars().last().unwrap();
-                    if last_char.is_digit(10) {
+                    if last_char.is_ascii_digit() {
                         let val = last_char.to_digit(10).unwrap() as u8;
                         if val <= 6 {
                             self.tokens.push(Token::new(TokenType::Syscall(val), span));
@@ -254,4 +285,4 @@ impl Lexer {
 
         self.current_char
     }
-}
\ No newline at end of file
+}
\ No newline at end of file
@@ -1,9 +1,20 @@
+// Parsing helpers report failures through `Result<_, ()>` and the `throw_exception*` functions.
+#![allow(clippy::result_unit_err)]
+
+pub mod bytecode;
+pub mod checker;
 pub mod compiler;
+pub mod constant;
+pub mod expansion;
+pub mod interpreter;
 pub mod operators;
 pub mod tokens;
+pub mod vm;
 pub mod lexer;
 pub mod parser;
+pub mod preprocessor;
 pub mod program;
+pub mod repl;
 
 use compiler::*;
 use parser::{Parser};
@@ -11,6 +22,7 @@ use tokens::Span;
 use lexer::Lexer;
 
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 fn main() {
     // Getting command line arguments
@@ -18,39 +30,176 @@ fn main() {
 
     // Checking and the arguments
     if args.len() == 1 && args[0] == "help" {
-        println!("USAGE:\n\tstapel build  <path>");
-        std::process::exit(0);
-    } else if args.len() != 2 {
-        println!("Please provide two arguments\nType: 'stapel --help' for help");
+        println!("USAGE:\n\tstapel build [options] <path>\n\tstapel run [options] <path> [arguments]\n\tstapel exec <path.splc> [arguments]\n\tstapel repl [options]\n\nOPTIONS:");
+        println!("\t--release             Leaves out assertions and panics");
+        println!("\t--checked             Checks the data stack for underflows and overflows");
+        println!("\t--checked-arith       Checks for division by zero and signed overflow");
+        println!("\t--stack-limit N       Amount of values the data stack can hold in checked builds and with --calls native");
+        println!("\t--ret-stack-size N    Entries of the return stack, the nesting depth of procedure calls");
+        println!("\t--ret-stack-max N     Lets the return stack grow up to N entries");
+        println!("\t--calls proxy|native  Calls procedures through call_proxy (default) or with call/ret");
+        println!("\t--no-tco              Keeps tail calls as normal calls, so they show up in backtraces");
+        println!("\t--lib                 Builds a static library and C header of the exported procedures");
+        println!("\t--bytecode            Builds a .splc bytecode file for 'stapel exec' instead of an executable");
+        println!("\t-D NAME=value         Defines a flag for conditional compilation, the value defaults to 1");
+        println!("\t-l NAME               Links the library, programs using extern procedures are linked with libc");
         std::process::exit(0);
-    } else if  args[0] != "build"  {
-        println!("'{}', is not a execution option.\nType: 'stapel --help' for help", args[1]);
+    } else if args.first().is_some_and(|a| a == "exec") {
+        exec(&args[1..]);
+    } else if args.is_empty() || !["build", "run", "repl"].contains(&args[0].as_str()) {
+        println!("'{}', is not a execution option.\nType: 'stapel help' for help", args.first().map_or("", |a| a.as_str()));
+        std::process::exit(1);
     }
 
-    if !args[1].ends_with(".spl") {
-        println!("File must end with '.spl', not {}", args[2]);
+    let interpret = args[0] == "run";
+    let repl = args[0] == "repl";
+    let mut options = CompilerOptions::default();
+    let mut program_arguments: Vec<String> = Vec::new();
+    let mut path: Option<String> = None;
+    let mut libraries: Vec<String> = Vec::new();
+    let mut emit_bytecode = false;
+    let mut arguments = args[1..].iter();
+    while let Some(arg) = arguments.next() {
+        match arg.as_str() {
+            "--release" => options.release = true,
+            "--checked" => options.checked = true,
+            "--checked-arith" => options.checked_arith = true,
+            "--no-tco" => options.no_tco = true,
+            "--lib" => options.library = true,
+            "--bytecode" => emit_bytecode = true,
+            "--stack-limit" => options.stack_limit = Some(parse_count(arg, arguments.next())),
+            "--ret-stack-size" => options.ret_stack_size = Some(parse_count(arg, arguments.next())),
+            "--ret-stack-max" => options.ret_stack_max = Some(parse_count(arg, arguments.next())),
+            "--calls" => options.call_convention = match arguments.next().map(|c| c.as_str()) {
+                Some("proxy") => CallConvention::Proxy,
+                Some("native") => CallConvention::Native,
+                _ => {
+                    println!("Option '--calls' expects a calling convention: proxy or native");
+                    std::process::exit(1);
+                }
+            },
+            "-D" => {
+                let Some(define) = arguments.next() else {
+                    println!("Option '-D' expects a flag: -D NAME=value");
+                    std::process::exit(1);
+                };
+                options.defines.push(parse_define(define));
+            }
+            define if define.starts_with("-D") => options.defines.push(parse_define(&define[2..])),
+            "-l" => {
+                let Some(library) = arguments.next() else {
+                    println!("Option '-l' expects a library: -l NAME");
+                    std::process::exit(1);
+                };
+                libraries.push(library.clone());
+            }
+            library if library.starts_with("-l") => libraries.push(library[2..].to_string()),
+            option if option.starts_with("--") => {
+                println!("'{}', is not a build option.\nType: 'stapel help' for help", option);
+                std::process::exit(1);
+            }
+            _ if repl => {
+                println!("'stapel repl' takes no path, load files with ':load <path>'");
+                std::process::exit(1);
+            }
+            _ if path.is_none() => {
+                path = Some(arg.clone());
+                // Everything after the path belongs to the interpreted program
+                if interpret {
+                    program_arguments.extend(arguments.by_ref().cloned());
+                }
+            }
+            _ => {
+                println!("Please provide one path to build\nType: 'stapel help' for help");
+                std::process::exit(1);
+            }
+        }
+    }
+    if options.ret_stack_max.is_some_and(|max| max < options.ret_stack_size.unwrap_or(RET_STACK_SIZE)) {
+        println!("'--ret-stack-max' can not be smaller than the size of the return stack");
+        std::process::exit(1);
+    }
+    if options.call_convention == CallConvention::Native && (options.ret_stack_size.is_some() || options.ret_stack_max.is_some()) {
+        println!("'--calls native' uses the hardware stack for return addresses, '--ret-stack-size' and '--ret-stack-max' do not apply");
+        std::process::exit(1);
+    }
+    if options.library && options.ret_stack_max.is_some() {
+        println!("'--ret-stack-max' can not be used with '--lib', the return stack of a library can not grow");
+        std::process::exit(1);
+    }
+    if emit_bytecode && (interpret || repl || options.library) {
+        println!("'--bytecode' can only be used with 'stapel build', and not together with '--lib'");
+        std::process::exit(1);
+    }
+    if (interpret || repl) && options.library {
+        println!("'--lib' can only be used with 'stapel build', a library has no main procedure to run");
+        std::process::exit(1);
+    }
+    if repl {
+        let code = interpreter::on_large_stack(&options.clone(), move || repl::run(options));
+        std::process::exit(code);
+    }
+    let Some(path) = path else {
+        println!("Please provide a path to build\nType: 'stapel help' for help");
+        std::process::exit(1);
+    };
+
+    if !path.ends_with(".spl") {
+        println!("File must end with '.spl', not {}", path);
         std::process::exit(1);
     }
 
     // Reading and parsing the file
-    let input = read_file(args[1].to_string());    
-    let mut l = Lexer::new(input, args[1].to_string());
+    let input = read_file(path.to_string());    
+    let mut l = Lexer::new(input, path.to_string());
     l.tokenize();
     
-    let mut p = Parser::new(l.tokens);
-    p.parse();
+    let tokens = preprocessor::process(l.tokens, &preprocessor::defines(&options));
+
+    let mut p = Parser::new(tokens);
+    if options.library {
+        p.parse_library();
+    } else {
+        p.parse();
+    }
+
+    checker::check(&p.program, &options);
 
-    let mut compiler = Compiler::new(p.program);
+    if interpret {
+        interpreter::resolve(&p.program, None);
+        let arguments = std::iter::once(path[..(path.len()-4)].to_string()).chain(program_arguments).collect::<Vec<String>>();
+        let program = p.program;
+        let code = interpreter::on_large_stack(&options.clone(), move || {
+            let mut machine = interpreter::Machine::new(options, &arguments);
+            interpreter::run(&program, &mut machine)
+        });
+        std::process::exit(code);
+    }
+
+    if emit_bytecode {
+        let bytecode_path = format!("{}.splc", &path[..(path.len()-4)]);
+        if std::fs::write(&bytecode_path, bytecode::lower(&p.program, &options).to_bytes()).is_err() {
+            println!("Could not save file at: {}", bytecode_path);
+            std::process::exit(1);
+        }
+        println!("[INFO] Compilation succesfull, run the bytecode with: 'stapel exec {}'", bytecode_path);
+        return;
+    }
+
+    let library = options.library;
+    let mut compiler = Compiler::new(p.program, options);
     compiler.compile_x86_64();
+    let links_libc = compiler.links_libc() || !libraries.is_empty();
+    let header = library.then(|| compiler.c_header());
 
     // Defining paths for compilation files
-    let assembly_path = format!("temp_{}.asm", args[1]);
-    let object_path = format!("temp_{}.asm.o", args[1]);
-    let executable_path = (&args[1][..(args[1].len()-4)]).to_string();
+    let assembly_path = format!("temp_{}.asm", path);
+    let object_path = format!("temp_{}.asm.o", path);
+    let executable_path = path[..(path.len()-4)].to_string();
 
     // Writing assembly file to fs
     let res = std::fs::write(&assembly_path, compiler.code);
-    if let Err(_) = res {
+    if res.is_err() {
         println!("Could not save file at: {}", assembly_path);
         std::process::exit(1);
     }
@@ -62,28 +211,108 @@ fn main() {
     let output = c.output();
     
     // Printing result from nasm
-    if let Err(_) = output {
+    if !succeeded(output) {
         println!("[ERROR] Failed to compile NASM to *.o");
         std::process::exit(1);
     }
     println!("[INFO] Sucessfully compiled NASM to object");
 
-    // Linking object
+    // Libraries are archived instead of linked, C programs link them with: cc main.c lib<name>.a -no-pie
+    if let Some(header) = header {
+        let directory = std::path::Path::new(&executable_path).parent().map_or(String::new(), |p| p.to_string_lossy().to_string());
+        let name = std::path::Path::new(&executable_path).file_name().unwrap().to_string_lossy().to_string();
+        let prefix = if directory.is_empty() { String::new() } else { format!("{}/", directory) };
+        let archive_path = format!("{}lib{}.a", prefix, name);
+        let header_path = format!("{}{}.h", prefix, name);
+
+        let mut c = Command::new("sh");
+        c.arg("-c").arg(format!("ar rcs {} {}", archive_path, object_path));
+        let output = c.output();
+        let _ = std::fs::remove_file(&object_path);
+        if !succeeded(output) || std::fs::write(&header_path, header).is_err() {
+            println!("[ERROR] Failed to create the library");
+            std::process::exit(1);
+        }
+        println!("[INFO] Compilation succesfull, library: './{}', header: './{}'", archive_path, header_path);
+        return;
+    }
+
+    // Linking object, through cc when C libraries are used. The program keeps its own _start,
+    // the dynamic loader initializes libc before it runs.
     let mut c = Command::new("sh");
-    c.arg("-c").arg(format!("ld -o {} {}", executable_path, object_path));
-    let _ = c.output();
+    if links_libc {
+        let libraries: String = libraries.iter().map(|library| format!(" -l{}", library)).collect();
+        c.arg("-c").arg(format!("cc -nostartfiles -no-pie -o {} {}{}", executable_path, object_path, libraries));
+    } else {
+        c.arg("-c").arg(format!("ld -o {} {}", executable_path, object_path));
+    }
+    let linked = succeeded(c.output());
 
     // Removing object file from compilation
     let res = std::fs::remove_file(object_path);
-    if let Err(_) = res {
+    if res.is_err() {
         println!("[ERROR] Failed to remove object file");
     }
 
     // Printing linking result
-    if let Err(_) = res {
+    if !linked {
         println!("[ERROR] Failed to compile object file to binary");
-    } else {
-        println!("[INFO] Compilation succesfull, path to executable: './{}'", executable_path);
+        std::process::exit(1);
+    }
+    println!("[INFO] Compilation succesfull, path to executable: './{}'", executable_path);
+}
+
+/// Whether a command of the build ran and exited successfully, its errors are shown when it failed
+fn succeeded(output: std::io::Result<std::process::Output>) -> bool {
+    match output {
+        Ok(output) if output.status.success() => true,
+        Ok(output) => {
+            print!("{}", String::from_utf8_lossy(&output.stderr));
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+/// Runs a `.splc` file built with `stapel build --bytecode`, the arguments after the path are passed to the program
+fn exec(args: &[String]) -> ! {
+    let Some(path) = args.first() else {
+        println!("Please provide a path to execute\nType: 'stapel help' for help");
+        std::process::exit(1);
+    };
+    let Ok(bytes) = std::fs::read(path) else {
+        println!("Could not read file at location: '{}'", path);
+        std::process::exit(1);
+    };
+    let program = match bytecode::Bytecode::from_bytes(&bytes) {
+        Ok(program) => program,
+        Err(message) => {
+            println!("Could not load '{}': {}", path, message);
+            std::process::exit(1);
+        }
+    };
+
+    let arguments: Vec<String> = std::iter::once(path.trim_end_matches(".splc").to_string()).chain(args[1..].iter().cloned()).collect();
+    let mut machine = interpreter::Machine::new(program.options(), &arguments);
+    std::process::exit(vm::exec(&program, &mut machine));
+}
+
+/// Parses the amount following an option, e.g. `--stack-limit 4096`
+fn parse_count(option: &str, value: Option<&String>) -> usize {
+    match value.and_then(|value| value.parse::<usize>().ok()) {
+        Some(count) if count > 0 => count,
+        _ => {
+            println!("Option '{}' expects a positive amount", option);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Splits `NAME=value` of a `-D` option, `NAME` alone sets the flag to 1
+fn parse_define(define: &str) -> (String, String) {
+    match define.split_once('=') {
+        Some((name, value)) => (name.to_string(), value.to_string()),
+        None => (define.to_string(), String::from("1")),
     }
 }
 
@@ -97,12 +326,28 @@ fn read_file(path: String) -> String {
     input
 }
 
+/// Set by the REPL, which reports errors in the input and keeps running
+static RECOVER_FROM_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Unwinds the stack instead of exiting on errors, the REPL catches them with `std::panic::catch_unwind`
+pub fn recover_from_errors() {
+    RECOVER_FROM_ERRORS.store(true, Ordering::Relaxed);
+}
+
 pub fn throw_exception_span(span: &Span, message: String) {
     println!("Syntax Error {} [{}:{}] ==>\n\t{}", span.file, span.line, span.column, message);
-    std::process::exit(1);
+    exit_with_error();
 }
 
 pub fn throw_exception(message: String) {
     println!("Compilation Error ==>\n\t{}", message);
+    exit_with_error();
+}
+
+fn exit_with_error() {
+    if RECOVER_FROM_ERRORS.load(Ordering::Relaxed) {
+        // Unlike panic! this skips the panic hook, the error was already reported
+        std::panic::resume_unwind(Box::new(()));
+    }
     std::process::exit(1);
 }
@@ -35,6 +35,47 @@ impl InfixOperators {
         }
     }
 
+    /// Spelling of the operator in Stapel source, the inverse of `new`
+    pub fn symbol(&self) -> &str {
+        match self {
+            InfixOperators::Plus => "+",
+            InfixOperators::Minus => "-",
+            InfixOperators::Multiply => "*",
+            InfixOperators::Divide => "/",
+            InfixOperators::Equals => "=",
+            InfixOperators::NotEquals => "!=",
+            InfixOperators::LesserThan => "<",
+            InfixOperators::GreaterThan => ">",
+            InfixOperators::GreaterOrEqualsTo => ">=",
+            InfixOperators::LesserOrEqualsTo => "<=",
+            InfixOperators::Modulo => "%",
+            InfixOperators::And => "and",
+            InfixOperators::Or => "or",
+        }
+    }
+
+    /// Applies the operator the same way the generated code does, `None` on division by zero and on
+    /// the one signed division that overflows (-2^63 / -1), which traps at runtime as well
+    pub fn evaluate(&self, left: i64, right: i64) -> Option<i64> {
+        let value = match self {
+            InfixOperators::Plus => left.wrapping_add(right),
+            InfixOperators::Minus => left.wrapping_sub(right),
+            InfixOperators::Multiply => left.wrapping_mul(right),
+            InfixOperators::Divide => left.checked_div(right)?,
+            InfixOperators::Modulo => left.checked_rem(right)?,
+            InfixOperators::Equals => (left == right) as i64,
+            InfixOperators::NotEquals => (left != right) as i64,
+            InfixOperators::GreaterThan => (left > right) as i64,
+            InfixOperators::LesserThan => (left < right) as i64,
+            InfixOperators::GreaterOrEqualsTo => (left >= right) as i64,
+            InfixOperators::LesserOrEqualsTo => (left <= right) as i64,
+            InfixOperators::And => (left != 0 && right != 0) as i64,
+            InfixOperators::Or => left | right,
+        };
+
+        Some(value)
+    }
+
     pub fn to_x86_64_instruction(&self) -> &str {
         match self {
             InfixOperators::Plus => "add",
@@ -3,18 +3,21 @@ use std::fmt::Display;
 
 use crate::operators::{InfixOperators};
 use crate::program::{Program};
-use crate::tokens::{Token, TokenType};
+use crate::{compiler, constant};
+use crate::tokens::{Span, Token, TokenType};
 use crate::{throw_exception, throw_exception_span};
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Instruction {
     pub instruction_type: InstructionType,
+    pub span: Span,
 }
 
 impl Instruction {
-    pub fn new(instruction_type: InstructionType) -> Instruction {
+    pub fn new(instruction_type: InstructionType, span: Span) -> Instruction {
         Instruction {
             instruction_type,
+            span,
         }
     }
 }
@@ -25,6 +28,19 @@ pub enum InstructionType {
     Push(PushType),
     InfixOperators(InfixOperators),
     While(While),
+    For(For),
+    Index,
+    Let(Let),
+    Binding(Binding),
+    Match(Match),
+    /// Expansion of an inline with arguments: `name(a, b)`
+    MacroCall(MacroCall),
+    /// Use of a parameter inside the body of an inline, holds its identifier and position
+    Argument(String, usize),
+    Assert,
+    /// Processed message of the panic
+    Panic(String),
+    Asm(Asm),
     If(If),
     Pop,
     Dup,
@@ -37,6 +53,8 @@ pub enum InstructionType {
     Store(usize),
     Identifier(String),
     Return,
+    Break,
+    Continue,
     Syscall(u8),
 }
 
@@ -46,6 +64,16 @@ impl InstructionType {
             InstructionType::Push(_) => 0,
             InstructionType::InfixOperators(_) => 2,
             InstructionType::While(_) => 1,
+            InstructionType::For(_) => 2,
+            InstructionType::Index => 0,
+            InstructionType::Let(lt) => lt.bindings.len() as u8,
+            InstructionType::Binding(_) => 0,
+            InstructionType::Match(_) => 1,
+            InstructionType::MacroCall(_) => 0,
+            InstructionType::Argument(_, _) => 0,
+            InstructionType::Assert => 1,
+            InstructionType::Panic(_) => 0,
+            InstructionType::Asm(asm) => asm.effect.inputs.len() as u8,
             InstructionType::If(_) => 1,
             InstructionType::Pop => 1,
             InstructionType::Swap => 2,
@@ -56,12 +84,49 @@ impl InstructionType {
             InstructionType::Dup => 1,
             InstructionType::Size => 0,
             InstructionType::Return => 0,
+            InstructionType::Break => 0,
+            InstructionType::Continue => 0,
             InstructionType::Load(_) => 1,
             InstructionType::Store(_) => 2,
             InstructionType::Syscall(registers) => *registers,
             InstructionType::Identifier(_) => 0,
         }
     }
+
+    pub fn pushes(&self) -> u8 {
+        match self {
+            InstructionType::Push(PushType::Int(_)) => 1,
+            InstructionType::Push(PushType::Str(_, _)) => 2,
+            InstructionType::InfixOperators(_) => 1,
+            InstructionType::While(_) => 0,
+            InstructionType::For(_) => 0,
+            InstructionType::Index => 1,
+            InstructionType::Let(_) => 0,
+            InstructionType::Binding(_) => 1,
+            InstructionType::Match(_) => 0,
+            InstructionType::MacroCall(_) => 0,
+            InstructionType::Argument(_, _) => 0,
+            InstructionType::Assert => 0,
+            InstructionType::Panic(_) => 0,
+            InstructionType::Asm(asm) => asm.effect.outputs.len() as u8,
+            InstructionType::If(_) => 0,
+            InstructionType::Pop => 0,
+            InstructionType::Swap => 2,
+            InstructionType::Rot => 3,
+            InstructionType::Over => 3,
+            InstructionType::Put => 0,
+            InstructionType::Pick => 0,
+            InstructionType::Dup => 2,
+            InstructionType::Size => 1,
+            InstructionType::Return => 0,
+            InstructionType::Break => 0,
+            InstructionType::Continue => 0,
+            InstructionType::Load(_) => 1,
+            InstructionType::Store(_) => 0,
+            InstructionType::Syscall(_) => 1,
+            InstructionType::Identifier(_) => 0,
+        }
+    }
 }
 
 impl Display for InstructionType {
@@ -73,16 +138,28 @@ impl Display for InstructionType {
             }
             InstructionType::InfixOperators(op) => format!("InfixOperator({})", op),
             InstructionType::While(_) => String::from("While"),
-            InstructionType::If(_) => format!("If"),
-            InstructionType::Pop => format!("Pop"),
-            InstructionType::Swap => format!("Swap"),
-            InstructionType::Rot => format!("Rot"),
-            InstructionType::Over => format!("Over"),
-            InstructionType::Pick => format!("Pick"),
-            InstructionType::Put => format!("Put"),
-            InstructionType::Dup => format!("Dup"),
-            InstructionType::Size => format!("Size"),
-            InstructionType::Return => format!("Return"),
+            InstructionType::For(_) => String::from("For"),
+            InstructionType::Index => String::from("Index"),
+            InstructionType::Let(_) => String::from("Let"),
+            InstructionType::Binding(binding) => format!("Binding({})", binding.identifier),
+            InstructionType::Match(_) => String::from("Match"),
+            InstructionType::MacroCall(call) => format!("MacroCall({})", call.identifier),
+            InstructionType::Argument(identifier, _) => format!("Argument({})", identifier),
+            InstructionType::Assert => String::from("Assert"),
+            InstructionType::Panic(message) => format!("Panic(\"{}\")", message),
+            InstructionType::Asm(_) => String::from("Asm"),
+            InstructionType::If(_) => "If".to_string(),
+            InstructionType::Pop => "Pop".to_string(),
+            InstructionType::Swap => "Swap".to_string(),
+            InstructionType::Rot => "Rot".to_string(),
+            InstructionType::Over => "Over".to_string(),
+            InstructionType::Pick => "Pick".to_string(),
+            InstructionType::Put => "Put".to_string(),
+            InstructionType::Dup => "Dup".to_string(),
+            InstructionType::Size => "Size".to_string(),
+            InstructionType::Return => "Return".to_string(),
+            InstructionType::Break => "Break".to_string(),
+            InstructionType::Continue => "Continue".to_string(),
             InstructionType::Load(i) => format!("Load({})", i),
             InstructionType::Store(i) => format!("Store({})", i),
             InstructionType::Syscall(syscall) => format!("Syscall({})", syscall),
@@ -185,15 +262,175 @@ impl While {
     }
 }
 
+/// Counted loop: `for <start> <end> do <block> end`, running from start up to (excluding) end.
+/// The current index is pushed by `i`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct For {
+    /// Leaves the start and end of the range on the stack
+    pub range: Block,
+    pub block: Block,
+}
+
+impl For {
+    pub fn parse(p: &mut Parser) -> Result<InstructionType, ()> {
+        p.next_token()?; // Skipping over FOR token
+        let range = Block::parse(p, &[TokenType::Do])?;
+
+        p.next_token()?;
+        let block = Block::parse(p, &[TokenType::End])?;
+
+        Ok(InstructionType::For(For { range, block }))
+    }
+}
+
+/// `match <value> case <constant> do <block> ... else <block> end` pops the value and runs the block
+/// of the case with the same constant, or the else block when there is none.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Match {
+    /// Leaves the value to match on the stack
+    pub value: Block,
+    pub cases: Vec<(i64, Block)>,
+    pub else_block: Option<Block>,
+}
+
+impl Match {
+    pub fn parse(p: &mut Parser) -> Result<InstructionType, ()> {
+        p.next_token()?; // Skipping over MATCH token
+        let value = Block::parse(p, &[TokenType::Case, TokenType::Else, TokenType::End])?;
+
+        let mut cases: Vec<(i64, Block)> = Vec::new();
+        while p.current_token()?.token == TokenType::Case {
+            let span = p.next_token()?.span.clone(); // Skipping over CASE token
+
+            // Cases are constant expressions, so enum variants and constant inlines can be used
+            let constant = Block::parse(p, &[TokenType::Do])?;
+            let constant = match constant::evaluate(&p.program, &constant)[..] {
+                [constant] => constant,
+                _ => {
+                    throw_exception_span(&span, "Define a case as: case <constant> do <block>. The constant should be exactly one value".to_string());
+                    unreachable!();
+                }
+            };
+            if cases.iter().any(|(c, _)| *c == constant) {
+                throw_exception_span(&span, format!("The value {} is matched by more than one case", constant));
+            }
+
+            p.next_token()?; // Skipping over DO token
+            let block = Block::parse(p, &[TokenType::Case, TokenType::Else, TokenType::End])?;
+            cases.push((constant, block));
+        }
+
+        let mut else_block = None;
+        if p.current_token()?.token == TokenType::Else {
+            p.next_token()?; // Skipping over ELSE token
+            else_block = Some(Block::parse(p, &[TokenType::End])?);
+        }
+
+        Ok(InstructionType::Match(Match { value, cases, else_block }))
+    }
+}
+
+/// A named value bound by `let`, the id is unique for every binding in the program
+#[derive(Debug, PartialEq, Clone)]
+pub struct Binding {
+    pub identifier: String,
+    pub id: usize,
+}
+
+/// `let a b c in <block> end` pops the top values of the stack into bindings, `c` being the top.
+/// Using a binding inside of the block pushes its value.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Let {
+    pub bindings: Vec<Binding>,
+    pub block: Block,
+}
+
+impl Let {
+    pub fn parse(p: &mut Parser) -> Result<InstructionType, ()> {
+        let mut bindings: Vec<Binding> = Vec::new();
+        let mut token = p.next_token()?.clone(); // Skipping over LET token
+        while token.token != TokenType::In {
+            let TokenType::Identifier(identifier) = token.token else {
+                throw_exception_span(&token.span, format!("Define bindings as: let <identifiers> in <block> end. \"{}\" can not be bound", token.token));
+                unreachable!();
+            };
+            if bindings.iter().any(|b| b.identifier == identifier) {
+                throw_exception_span(&token.span, format!("'{}' is bound twice by the same let", identifier));
+            }
+
+            bindings.push(Binding { identifier, id: p.binding_count });
+            p.binding_count += 1;
+            token = p.next_token()?.clone();
+        }
+
+        // Bindings shadow everything with the same name, including bindings of enclosing lets
+        p.next_token()?; // Skipping over IN token
+        p.let_scopes.push(bindings.clone());
+        let block = Block::parse(p, &[TokenType::End]);
+        p.let_scopes.pop();
+
+        Ok(InstructionType::Let(Let { bindings, block: block? }))
+    }
+}
+
+/// Raw NASM written as `asm <inputs> -- <outputs> do "<line>"... end`, which works on the data stack
+/// with the `dpush`/`dpop` macros. The declared stack effect is what the checker assumes it does.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Asm {
+    pub effect: StackEffect,
+    /// Lines of assembly, as written between the quotes
+    pub lines: Vec<String>,
+}
+
+impl Asm {
+    pub fn parse(p: &mut Parser) -> Result<InstructionType, ()> {
+        let usage = "Write inline assembly as: asm <inputs> -- <outputs> do \"<line>\"... end";
+        p.next_token()?; // Skipping over ASM token
+        let effect = p.parse_stack_effect(usage, TokenType::Do)?;
+
+        let mut lines = Vec::new();
+        let mut token = p.next_token()?.clone(); // Skipping over DO token
+        while token.token != TokenType::End {
+            let TokenType::PushStr(_, original) = token.token else {
+                throw_exception_span(&token.span, format!("{}. Every line should be a string literal", usage));
+                unreachable!();
+            };
+            // A global label would become the scope of the local labels the compiler uses for the rest of the procedure
+            if let Some(label) = Asm::label(&original).filter(|label| !label.starts_with('.') && !label.starts_with("%$")) {
+                throw_exception_span(&token.span, format!("'{}' would end the procedure, labels in asm blocks should be local: %${} or .{}", label, label, label));
+            }
+            lines.push(original);
+            token = p.next_token()?.clone();
+        }
+
+        Ok(InstructionType::Asm(Asm { effect, lines }))
+    }
+
+    /// The label a line of NASM defines, written as `<label>:` in front of the instruction
+    fn label(line: &str) -> Option<&str> {
+        let line = line.split(';').next().unwrap_or_default();
+        let (label, _) = line.split_once(':')?;
+        let label = label.trim();
+        (!label.is_empty() && !label.contains(char::is_whitespace)).then_some(label)
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Procedure {
     pub identifier: String,
     pub block: Block,
+    /// Location of the identifier, shown in backtraces
+    pub span: Span,
+    /// Stack effect of `export proc`, which makes it callable from C in `--lib` builds
+    pub export: Option<StackEffect>,
 }
 
 impl Procedure {
-    pub fn parse(p: &mut Parser) -> Result<Procedure, ()> {
+    /// Parses `proc <identifier> do <block> end`, or `export proc <identifier> <inputs> -- <output> do <block> end`
+    /// when `exported`, starting at the PROC token
+    pub fn parse(p: &mut Parser, exported: bool) -> Result<Procedure, ()> {
         let identifier = p.next_token()?; // Skipping the PROC token
+        let span = identifier.span.clone();
 
         let TokenType::Identifier(identifier) = identifier.token.clone() else { // Getting the IDENTIFIER
             throw_exception_span(&identifier.span, "Define a procudure as: proc <identifier> do <block> end. You forgot the identifier".to_string());
@@ -201,37 +438,101 @@ impl Procedure {
         };
 
         // Checking if identifier already exists
-        if p.procedures_identifiers.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already a procedure name", identifier));
-        } else if p.inline_statements.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already an inline name", identifier));
-        } else if p.memories.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already a memory ", identifier));
-        }
+        p.check_identifier_available(&identifier);
 
         p.next_token()?; // Going to the DO token
+        let export = if exported {
+            let effect = p.parse_stack_effect("Export a procedure as: export proc <identifier> <inputs> -- <output> do <block> end", TokenType::Do)?;
+            effect.check_c_abi(&span, &identifier);
+            if compiler::is_runtime_label(&identifier) {
+                throw_exception_span(&span, format!("'{}' is a label of the runtime, exported procedures can not use its name", identifier));
+            }
+            Some(effect)
+        } else {
+            None
+        };
         if !p.current_token_is(TokenType::Do) {
             throw_exception_span(&p.current_token().unwrap().span, "Define a procudure as: proc <identifier> do <block> end. You forgot the \"do\" instruction".to_string());
         }
         p.next_token()?; // Skipping over the DO token, going to block
         
         let mut block = Block::parse(p, &[TokenType::End])?; // Getting the procedure block
+        let end_span = p.current_token()?.span.clone();
         let _ = p.next_token(); // Is Err(()) when at end of file
         if (block.instructions.is_empty() || block.instructions.last().unwrap().instruction_type != InstructionType::Return)
             && identifier != "main"
         {
             block
                 .instructions
-                .push(Instruction::new(InstructionType::Return));
+                .push(Instruction::new(InstructionType::Return, end_span));
         }
 
-        Ok(Procedure { identifier, block })
+        Ok(Procedure { identifier, block, span, export })
+    }
+}
+
+/// Names of the values something takes from the stack and leaves on it, written as `<inputs> -- <outputs>`.
+/// The names only document the values, the first input is the deepest on the stack. The one exception is an
+/// output of an `extern proc` named `int`, which is a 32 bit C `int` and is sign-extended from 32 bits.
+#[derive(Debug, PartialEq, Clone)]
+pub struct StackEffect {
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+}
+
+impl StackEffect {
+    /// Arguments fit in the System V argument registers, the result is returned in `rax`
+    pub const MAX_C_INPUTS: usize = 6;
+
+    /// Exits with an error when a C function can not have this stack effect
+    pub fn check_c_abi(&self, span: &Span, identifier: &String) {
+        if self.inputs.len() > StackEffect::MAX_C_INPUTS {
+            throw_exception_span(span, format!("'{}' takes {} arguments, C functions can take at most {}", identifier, self.inputs.len(), StackEffect::MAX_C_INPUTS));
+        }
+        if self.outputs.len() > 1 {
+            throw_exception_span(span, format!("'{}' returns {} values, C functions return at most one", identifier, self.outputs.len()));
+        }
+    }
+}
+
+/// A C function declared with `extern proc <identifier> <inputs> -- <output> end`, called with the System V ABI
+#[derive(Debug, PartialEq, Clone)]
+pub struct Extern {
+    pub identifier: String,
+    pub effect: StackEffect,
+}
+
+impl Extern {
+    pub fn parse(p: &mut Parser) -> Result<Extern, ()> {
+        let span = p.current_token()?.span.clone();
+        let usage = "Declare a C function as: extern proc <identifier> <inputs> -- <output> end";
+
+        if p.next_token()?.token != TokenType::Procedure { // Skipping the EXTERN token
+            throw_exception_span(&p.current_token()?.span, format!("{}. You forgot \"proc\"", usage));
+        }
+        let identifier = p.next_token()?; // Skipping the PROC token
+        let TokenType::Identifier(identifier) = identifier.token.clone() else { // Getting the IDENTIFIER
+            throw_exception_span(&identifier.span, format!("{}. You forgot the identifier", usage));
+            unreachable!();
+        };
+
+        // Checking if identifier already exists
+        p.check_identifier_available(&identifier);
+
+        p.next_token()?; // Skipping over the IDENTIFIER token
+        let effect = p.parse_stack_effect(usage, TokenType::End)?;
+        let _ = p.next_token(); // skipping over END
+        effect.check_c_abi(&span, &identifier);
+
+        Ok(Extern { identifier, effect })
     }
 }
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Inline {
     pub identifier: String,
+    /// Names of the arguments of `inline name(a b) ... end`, empty for a plain inline
+    pub parameters: Vec<String>,
     pub block: Block,
 }
 
@@ -245,20 +546,93 @@ impl Inline {
         };
 
         // Checking if identifier already exists
-        if p.procedures_identifiers.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already a procedure name", identifier));
-        } else if p.inline_statements.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already an inline name", identifier));
-        } else if p.memories.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already a memory ", identifier));
-        }
+        p.check_identifier_available(&identifier);
 
-        p.next_token()?; // Skipping over the IDENTIFIER token, going to block
+        let mut parameters: Vec<String> = Vec::new();
+        let mut token = p.next_token()?.clone(); // Skipping over the IDENTIFIER token, going to block
+        if token.token == TokenType::OpenParen {
+            token = p.next_token()?.clone();
+            while token.token != TokenType::CloseParen {
+                match token.token {
+                    TokenType::Identifier(parameter) if !parameters.contains(&parameter) => parameters.push(parameter),
+                    TokenType::Identifier(parameter) => {
+                        throw_exception_span(&token.span, format!("Inline '{}' has more than one parameter named '{}'", identifier, parameter));
+                    }
+                    TokenType::Comma => (),
+                    _ => {
+                        throw_exception_span(&token.span, format!("Define an inline with parameters as: inline <identifier>(<identifiers>) <block> end. \"{}\" can not be a parameter", token.token));
+                    }
+                }
+                token = p.next_token()?.clone();
+            }
+            p.next_token()?; // Skipping over the CLOSEPAREN token
+        }
 
-        let block = Block::parse(p, &[TokenType::End])?; // Getting the procedure block
+        p.inline_parameters = parameters.clone();
+        let block = Block::parse(p, &[TokenType::End]); // Getting the procedure block
+        p.inline_parameters.clear();
         let _ = p.next_token(); // Is Err(()) when at end of file
 
-        Ok(Inline {identifier, block})
+        Ok(Inline {identifier, parameters, block: block?})
+    }
+
+    /// Exits with an error when the inline is expanded with the wrong amount of arguments
+    pub fn check_arguments(&self, span: &Span, arguments: usize) {
+        if arguments != self.parameters.len() {
+            throw_exception_span(span, format!("Inline '{}' takes {} argument(s), but {} were given", self.identifier, self.parameters.len(), arguments));
+        }
+    }
+}
+
+/// Expansion of an inline with arguments, every argument is a block that is compiled where the
+/// inline uses the matching parameter.
+#[derive(Debug, PartialEq, Clone)]
+pub struct MacroCall {
+    pub identifier: String,
+    pub arguments: Vec<Block>,
+}
+
+impl MacroCall {
+    pub fn parse(p: &mut Parser, identifier: String) -> Result<InstructionType, ()> {
+        p.next_token()?; // Skipping over the IDENTIFIER token
+        let mut token = p.next_token()?.token.clone(); // Skipping over the OPENPAREN token
+
+        let mut arguments: Vec<Block> = Vec::new();
+        while token != TokenType::CloseParen {
+            if !arguments.is_empty() {
+                p.next_token()?; // Skipping over the COMMA token
+            }
+            arguments.push(Block::parse(p, &[TokenType::Comma, TokenType::CloseParen])?);
+            token = p.current_token()?.token.clone();
+        }
+
+        Ok(InstructionType::MacroCall(MacroCall { identifier, arguments }))
+    }
+}
+
+/// `static_assert <condition> "<message>" end` on toplevel, the condition is a constant expression
+/// that is evaluated while parsing. Compilation stops with the message when it is 0.
+pub struct StaticAssert;
+
+impl StaticAssert {
+    pub fn parse(p: &mut Parser) -> Result<(), ()> {
+        let span = p.current_token()?.span.clone();
+        p.next_token()?; // Skipping over STATIC_ASSERT token
+
+        let mut condition = Block::parse(p, &[TokenType::End])?;
+        let _ = p.next_token(); // skipping over END
+
+        let Some(Instruction { instruction_type: InstructionType::Push(PushType::Str(message, _)), .. }) = condition.instructions.pop() else {
+            throw_exception_span(&span, "Define a static assertion as: static_assert <condition> \"<message>\" end. You forgot the message".to_string());
+            unreachable!();
+        };
+
+        match constant::evaluate(&p.program, &condition)[..] {
+            [0] => throw_exception_span(&span, format!("Static assertion failed: {}", message)),
+            [_] => (),
+            _ => throw_exception_span(&span, "Define a static assertion as: static_assert <condition> \"<message>\" end. The condition should be exactly one value".to_string()),
+        }
+        Ok(())
     }
 }
 
@@ -279,24 +653,317 @@ impl Memory {
         };
 
         // Checking if identifier already exists
-        if p.procedures_identifiers.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already a procedure name", identifier));
-        } else if p.inline_statements.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already an inline name", identifier));
-        } else if p.memories.contains(&identifier) {
-            throw_exception_span(&p.current_token().unwrap().span, format!("inline named '{}', is already a memory ", identifier));
+        p.check_identifier_available(&identifier);
+
+        let span = p.next_token()?.span.clone(); // Skipping over the IDENTIFIER token, going to SIZE
+
+        // The size can be any constant expression, e.g. `memory table 8 256 * end`
+        let size = Block::parse(p, &[TokenType::End])?;
+        let _ = p.next_token(); // skipping over END
+
+        let size = match constant::evaluate(&p.program, &size)[..] {
+            [size] if size >= 0 => size,
+            [size] => {
+                throw_exception_span(&span, format!("The size of memory '{}' can not be negative ({})", identifier, size));
+                unreachable!();
+            }
+            _ => {
+                throw_exception_span(&span, "Define a memory as: memory <identifier> <size> end. The size should be exactly one value".to_string());
+                unreachable!();
+            }
+        };
+
+        Ok(Memory {identifier, size: size as usize})
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DataValue {
+    Int(i64),
+    Str(String, String),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Data {
+    pub identifier: String,
+    /// Width in bytes of every integer value
+    pub width: usize,
+    pub values: Vec<DataValue>,
+}
+
+impl Data {
+    pub fn parse(p: &mut Parser) -> Result<Data, ()> {
+        let identifier = p.next_token()?; // Skipping the DATA token
+
+        let TokenType::Identifier(identifier) = identifier.token.clone() else { // Getting the IDENTIFIER
+            throw_exception_span(&identifier.span, "Define data as: data <identifier> <width> <values> end. You forgot the identifier".to_string());
+            unreachable!();
+        };
+
+        // Checking if identifier already exists
+        p.check_identifier_available(&identifier);
+
+        let width = p.next_token()?; // Skipping over the IDENTIFIER token, going to WIDTH
+        let TokenType::PushInt(width) = width.token.clone() else {
+            throw_exception_span(&width.span, "Define data as: data <identifier> <width> <values> end. You forgot the width".to_string());
+            unreachable!();
+        };
+        if ![1, 2, 4, 8].contains(&width) {
+            throw_exception_span(&p.current_token()?.span, format!("'{}' is not a supported data width, use 1, 2, 4 or 8", width));
+        }
+        let width = width as usize;
+
+        let mut values = Vec::new();
+        let mut expression = Block { instructions: Vec::new() };
+        p.next_token()?; // Skipping over WIDTH
+        while !p.current_token_is(TokenType::End) {
+            let instruction = p.parse_instruction()?;
+            if let InstructionType::Push(PushType::Str(str, original)) = &instruction.instruction_type {
+                if width != 1 && width != 8 {
+                    throw_exception_span(&instruction.span, "Strings can only be used in data with a width of 1 (bytes) or 8 (length and address)".to_string());
+                }
+                Data::push_expression(p, &mut expression, width, &mut values);
+                values.push(DataValue::Str(str.clone(), original.clone()));
+            } else {
+                expression.instructions.push(instruction);
+            }
+            p.next_token()?;
         }
+        Data::push_expression(p, &mut expression, width, &mut values);
+        let _ = p.next_token(); // skipping over END
+
+        Ok(Data { identifier, width, values })
+    }
+
+    /// Evaluates the pending constant expression and adds the resulting stack as integer values
+    fn push_expression(p: &Parser, expression: &mut Block, width: usize, values: &mut Vec<DataValue>) {
+        let Some(first) = expression.instructions.first() else {
+            return;
+        };
+        let span = first.span.clone();
+
+        for int in constant::evaluate(&p.program, expression) {
+            if !Data::fits(int, width) {
+                throw_exception_span(&span, format!("'{}' does not fit in {} byte(s)", int, width));
+            }
+            values.push(DataValue::Int(int));
+        }
+        expression.instructions.clear();
+    }
+
+    /// Whether the value can be stored in `width` bytes, either as signed or unsigned integer
+    fn fits(value: i64, width: usize) -> bool {
+        if width == 8 {
+            return true;
+        }
+        let bits = width as u32 * 8;
+        value >= -(1i64 << (bits - 1)) && value < (1i64 << bits)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Field {
+    pub identifier: String,
+    pub offset: usize,
+    /// Size in bytes
+    pub size: usize,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Struct {
+    pub identifier: String,
+    pub fields: Vec<Field>,
+    /// Total size in bytes
+    pub size: usize,
+    pub span: Span,
+}
 
-        let size = p.next_token()?; // Skipping over the IDENTIFIER token, going to SIZE
+impl Struct {
+    pub fn parse(p: &mut Parser) -> Result<Struct, ()> {
+        let identifier = p.next_token()?; // Skipping the STRUCT token
+        let span = identifier.span.clone();
 
-        let TokenType::PushInt(size) = size.token.clone() else { // Getting the IDENTIFIER
-            throw_exception_span(&size.span, "Define a procudure as: memory <identifier> <size> end. You forgot the identifier".to_string());
+        let TokenType::Identifier(identifier) = identifier.token.clone() else { // Getting the IDENTIFIER
+            throw_exception_span(&identifier.span, "Define a struct as: struct <identifier> <field> <size> ... end. You forgot the identifier".to_string());
             unreachable!();
         };
-        let _ = p.next_token(); // skipping over SIZE
+
+        // Checking if identifier already exists
+        p.check_identifier_available(&identifier);
+
+        let mut fields: Vec<Field> = Vec::new();
+        let mut offset = 0;
+        p.next_token()?; // Skipping over the IDENTIFIER token, going to the first field
+        while !p.current_token_is(TokenType::End) {
+            let field = p.current_token()?.clone();
+            let Some(field_identifier) = field.token.word() else {
+                throw_exception_span(&field.span, format!("Expected the name of a field in struct '{}', not \"{}\"", identifier, field.token));
+                unreachable!();
+            };
+            if fields.iter().any(|f| f.identifier == field_identifier) {
+                throw_exception_span(&field.span, format!("Struct '{}' already has a field named '{}'", identifier, field_identifier));
+            }
+
+            // The size is a single constant, either a literal or an inline such as the size of another struct
+            p.next_token()?; // Going to the SIZE
+            let size = p.parse_instruction()?;
+            let size = match constant::evaluate(&p.program, &Block { instructions: vec![size] })[..] {
+                [size] if size > 0 => size as usize,
+                _ => {
+                    throw_exception_span(&field.span, format!("The size of field '{}' should be one positive constant", field_identifier));
+                    unreachable!();
+                }
+            };
+
+            fields.push(Field { identifier: field_identifier, offset, size, span: field.span });
+            offset += size;
+            p.next_token()?;
+        }
         let _ = p.next_token(); // skipping over END
 
-        Ok(Memory {identifier, size: size as usize})
+        Ok(Struct { identifier, fields, size: offset, span })
+    }
+
+    /// The inlines a struct is used through:
+    /// - `<struct>` pushes the total size, so it can be used as memory size
+    /// - `<struct>.<field>` pushes the offset of the field
+    /// - `<struct>.<field>!` ( ptr -- value ) loads the field, for fields of 1, 2, 4 or 8 bytes
+    /// - `<struct>.<field>@` ( ptr value -- ) stores the field, for fields of 1, 2, 4 or 8 bytes
+    pub fn inlines(&self) -> Vec<Inline> {
+        let constant = |identifier: String, value: usize, span: &Span| Inline {
+            identifier,
+            parameters: Vec::new(),
+            block: Block { instructions: vec![Instruction::new(InstructionType::Push(PushType::Int(value as i64)), span.clone())] },
+        };
+
+        let mut inlines = vec![constant(self.identifier.clone(), self.size, &self.span)];
+        for field in &self.fields {
+            let name = format!("{}.{}", self.identifier, field.identifier);
+            inlines.push(constant(name.clone(), field.offset, &field.span));
+
+            if ![1, 2, 4, 8].contains(&field.size) {
+                continue;
+            }
+
+            let instruction = |instruction_type: InstructionType| Instruction::new(instruction_type, field.span.clone());
+            inlines.push(Inline {
+                identifier: format!("{}!", name),
+                parameters: Vec::new(),
+                block: Block { instructions: vec![
+                    instruction(InstructionType::Push(PushType::Int(field.offset as i64))),
+                    instruction(InstructionType::InfixOperators(InfixOperators::Plus)),
+                    instruction(InstructionType::Load(field.size)),
+                ]},
+            });
+            inlines.push(Inline {
+                identifier: format!("{}@", name),
+                parameters: Vec::new(),
+                block: Block { instructions: vec![
+                    instruction(InstructionType::Swap),
+                    instruction(InstructionType::Push(PushType::Int(field.offset as i64))),
+                    instruction(InstructionType::InfixOperators(InfixOperators::Plus)),
+                    instruction(InstructionType::Swap),
+                    instruction(InstructionType::Store(field.size)),
+                ]},
+            });
+        }
+
+        inlines
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Enum {
+    pub identifier: String,
+    /// Variants in order, the value of a variant is its index
+    pub variants: Vec<(String, Span)>,
+    pub span: Span,
+}
+
+impl Enum {
+    /// Generated identifiers, which can not be used as variant names
+    const RESERVED: [&'static str; 3] = ["count", "name", "names"];
+
+    pub fn parse(p: &mut Parser) -> Result<Enum, ()> {
+        let identifier = p.next_token()?; // Skipping the ENUM token
+        let span = identifier.span.clone();
+
+        let TokenType::Identifier(identifier) = identifier.token.clone() else { // Getting the IDENTIFIER
+            throw_exception_span(&identifier.span, "Define an enum as: enum <identifier> <variants> end. You forgot the identifier".to_string());
+            unreachable!();
+        };
+
+        // Checking if identifier already exists
+        p.check_identifier_available(&identifier);
+
+        let mut variants: Vec<(String, Span)> = Vec::new();
+        p.next_token()?; // Skipping over the IDENTIFIER token, going to the first variant
+        while !p.current_token_is(TokenType::End) {
+            let variant = p.current_token()?.clone();
+            let Some(variant_identifier) = variant.token.word() else {
+                throw_exception_span(&variant.span, format!("Expected the name of a variant in enum '{}', not \"{}\"", identifier, variant.token));
+                unreachable!();
+            };
+            if Enum::RESERVED.contains(&variant_identifier.as_str()) {
+                throw_exception_span(&variant.span, format!("'{}' can not be used as variant name, it is generated for every enum", variant_identifier));
+            } else if variants.iter().any(|(v, _)| *v == variant_identifier) {
+                throw_exception_span(&variant.span, format!("Enum '{}' already has a variant named '{}'", identifier, variant_identifier));
+            }
+
+            variants.push((variant_identifier, variant.span));
+            p.next_token()?;
+        }
+        let _ = p.next_token(); // skipping over END
+
+        Ok(Enum { identifier, variants, span })
+    }
+
+    /// The inlines an enum is used through:
+    /// - `<enum>.<variant>` pushes the value of the variant, counting up from 0
+    /// - `<enum>.count` pushes the amount of variants
+    /// - `<enum>.name` ( value -- len addr ) pushes the name of the variant with that value
+    pub fn inlines(&self) -> Vec<Inline> {
+        let constant = |identifier: String, value: usize, span: &Span| Inline {
+            identifier,
+            parameters: Vec::new(),
+            block: Block { instructions: vec![Instruction::new(InstructionType::Push(PushType::Int(value as i64)), span.clone())] },
+        };
+
+        let mut inlines: Vec<Inline> = self.variants.iter().enumerate()
+            .map(|(i, (variant, span))| constant(format!("{}.{}", self.identifier, variant), i, span))
+            .collect();
+        inlines.push(constant(format!("{}.count", self.identifier), self.variants.len(), &self.span));
+
+        // Every entry in the name table is a [length, address] pair of 16 bytes
+        let instruction = |instruction_type: InstructionType| Instruction::new(instruction_type, self.span.clone());
+        inlines.push(Inline {
+            identifier: format!("{}.name", self.identifier),
+            parameters: Vec::new(),
+            block: Block { instructions: vec![
+                instruction(InstructionType::Push(PushType::Int(16))),
+                instruction(InstructionType::InfixOperators(InfixOperators::Multiply)),
+                instruction(InstructionType::Identifier(format!("{}.names", self.identifier))),
+                instruction(InstructionType::InfixOperators(InfixOperators::Plus)),
+                instruction(InstructionType::Dup),
+                instruction(InstructionType::Load(8)),
+                instruction(InstructionType::Swap),
+                instruction(InstructionType::Push(PushType::Int(8))),
+                instruction(InstructionType::InfixOperators(InfixOperators::Plus)),
+                instruction(InstructionType::Load(8)),
+            ]},
+        });
+
+        inlines
+    }
+
+    /// The name table `<enum>.names`, holding the name of every variant as [length, address] pair
+    pub fn names(&self) -> Data {
+        Data {
+            identifier: format!("{}.names", self.identifier),
+            width: 8,
+            values: self.variants.iter().map(|(variant, _)| DataValue::Str(variant.clone(), variant.clone())).collect(),
+        }
     }
 }
 
@@ -306,6 +973,15 @@ pub struct Parser {
     procedures_identifiers: HashSet<String>,
     inline_statements: HashSet<String>,
     memories: HashSet<String>,
+    datas: HashSet<String>,
+    externs: HashSet<String>,
+    /// Bindings of the lets around the instruction being parsed, innermost last
+    let_scopes: Vec<Vec<Binding>>,
+    /// Parameters of the inline being parsed
+    inline_parameters: Vec<String>,
+    binding_count: usize,
+    /// Set by the REPL, a declaration replaces an earlier one with the same name instead of failing
+    replace_declarations: bool,
     tokens: Vec<Token>,
     cursor: usize,
 }
@@ -327,50 +1003,135 @@ impl Parser {
             procedures_identifiers: HashSet::new(),
             inline_statements: HashSet::new(),
             memories,
+            datas: HashSet::new(),
+            externs: HashSet::new(),
+            let_scopes: Vec::new(),
+            inline_parameters: Vec::new(),
+            binding_count: 0,
+            replace_declarations: false,
         }
     }
 
+    /// Parses a program, which starts at its `main` procedure
     pub fn parse(&mut self) {
-        loop {
-            if let Ok(token) = self.current_token() {
-                if let TokenType::Procedure = token.token {
-                    let Ok(proc) = Procedure::parse(self) else {
-                        panic!("Cannot parse procedure")
-                    };
-
-                    self.procedures_identifiers.insert(proc.identifier.clone());
-                    self.program.procedures.insert(proc.identifier.clone(), proc);
-                } else if let TokenType::Memory = token.token {
-                    let Ok(memory) = Memory::parse(self) else {
-                        panic!("Cannot parse memory statement")
-                    };
-
-                    self.memories.insert(memory.identifier.clone());
-                    self.program.memories.insert(memory.identifier.clone(), memory);
+        self.parse_declarations();
+
+        if !self.procedures_identifiers.contains("main") {
+            throw_exception("No entry point is found in this program. Make sure there is a procedure named \"main\"".to_string())
+        }
+    }
+
+    /// Parses more source into the same program, as the REPL does with every input. Declarations are
+    /// added to the program, replacing earlier ones with the same name. Other input is a line of
+    /// instructions which is returned to be executed.
+    pub fn parse_input(&mut self, tokens: Vec<Token>) -> Block {
+        self.tokens = tokens;
+        self.cursor = 0;
+        self.replace_declarations = true;
+
+        let declaration = [TokenType::Procedure, TokenType::Export, TokenType::Memory, TokenType::Inline, TokenType::Data, TokenType::Extern, TokenType::StaticAssert, TokenType::Struct, TokenType::Enum];
+        if self.current_token().is_ok_and(|token| declaration.contains(&token.token)) {
+            self.parse_declarations();
+            return Block { instructions: Vec::new() };
+        }
+
+        let Ok(block) = Block::parse(self, &[]) else {
+            throw_exception("The input ends in the middle of an instruction".to_string());
+            unreachable!();
+        };
+        block
+    }
+
+    /// Parses a library for `--lib` builds, which is used through its exported procedures
+    pub fn parse_library(&mut self) {
+        self.parse_declarations();
+
+        if self.program.procedures.values().all(|procedure| procedure.export.is_none()) {
+            throw_exception("This library exports nothing. Make procedures callable from C with: export proc <identifier> <inputs> -- <output> do <block> end".to_string())
+        }
+    }
+
+    fn parse_declarations(&mut self) {
+        while let Ok(token) = self.current_token() {
+            if let TokenType::Procedure | TokenType::Export = token.token {
+                let exported = token.token == TokenType::Export;
+                if exported && !self.next_token().is_ok_and(|t| t.token == TokenType::Procedure) {
+                    let span = self.tokens[self.cursor.min(self.tokens.len() - 1)].span.clone();
+                    throw_exception_span(&span, "Only procedures can be exported: export proc <identifier> <inputs> -- <output> do <block> end".to_string());
                 }
-                else if let TokenType::Inline = token.token {
-                    let Ok(inline) = Inline::parse(self) else {
-                        panic!("Cannot parse inline statement")
-                    };
-                    
+                let Ok(proc) = Procedure::parse(self, exported) else {
+                    panic!("Cannot parse procedure")
+                };
+
+                self.procedures_identifiers.insert(proc.identifier.clone());
+                self.program.procedures.insert(proc.identifier.clone(), proc);
+            } else if let TokenType::Memory = token.token {
+                let Ok(memory) = Memory::parse(self) else {
+                    panic!("Cannot parse memory statement")
+                };
+
+                self.memories.insert(memory.identifier.clone());
+                self.program.memories.insert(memory.identifier.clone(), memory);
+            }
+            else if let TokenType::Inline = token.token {
+                let Ok(inline) = Inline::parse(self) else {
+                    panic!("Cannot parse inline statement")
+                };
+                
+                self.inline_statements.insert(inline.identifier.clone());
+                self.program.inlines.insert(inline.identifier.clone(), inline);
+            } else if let TokenType::Data = token.token {
+                let Ok(data) = Data::parse(self) else {
+                    panic!("Cannot parse data statement")
+                };
+
+                self.datas.insert(data.identifier.clone());
+                self.program.datas.insert(data.identifier.clone(), data);
+            } else if let TokenType::Extern = token.token {
+                let Ok(extern_proc) = Extern::parse(self) else {
+                    panic!("Cannot parse extern statement")
+                };
+
+                self.externs.insert(extern_proc.identifier.clone());
+                self.program.externs.insert(extern_proc.identifier.clone(), extern_proc);
+            } else if let TokenType::StaticAssert = token.token {
+                if StaticAssert::parse(self).is_err() {
+                    panic!("Cannot parse static_assert statement")
+                }
+            } else if let TokenType::Struct = token.token {
+                let Ok(strct) = Struct::parse(self) else {
+                    panic!("Cannot parse struct statement")
+                };
+
+                for inline in strct.inlines() {
+                    self.check_identifier_available_at(&inline.identifier, &strct.span);
+                    self.inline_statements.insert(inline.identifier.clone());
+                    self.program.inlines.insert(inline.identifier.clone(), inline);
+                }
+            } else if let TokenType::Enum = token.token {
+                let Ok(enm) = Enum::parse(self) else {
+                    panic!("Cannot parse enum statement")
+                };
+
+                for inline in enm.inlines() {
+                    self.check_identifier_available_at(&inline.identifier, &enm.span);
                     self.inline_statements.insert(inline.identifier.clone());
                     self.program.inlines.insert(inline.identifier.clone(), inline);
-                } else {
-                    throw_exception_span(&token.span, format!("\"{:?}\" should be a procedure declaration, no instructions are allowed on toplevel", token.token));
                 }
+
+                let names = enm.names();
+                self.check_identifier_available_at(&names.identifier, &enm.span);
+                self.datas.insert(names.identifier.clone());
+                self.program.datas.insert(names.identifier.clone(), names);
             } else {
-                // No tokens left
-                break;
+                throw_exception_span(&token.span, format!("\"{:?}\" should be a procedure declaration, no instructions are allowed on toplevel", token.token));
             }
         }
-
-        if !self.procedures_identifiers.contains("main") {
-            throw_exception("No entry point is found in this program. Make sure there is a procedure named \"main\"".to_string())
-        }
     }
 
     fn parse_instruction(&mut self) -> Result<Instruction, ()> {
         let token = self.current_token()?;
+        let span = token.span.clone();
         let instruction_type = match &token.token {
             TokenType::PushInt(int) => InstructionType::Push(PushType::Int(*int)),
             TokenType::PushStr(original, value) => {
@@ -388,11 +1149,44 @@ impl Parser {
             TokenType::Dup => InstructionType::Dup,
             TokenType::Size => InstructionType::Size,
             TokenType::Return => InstructionType::Return,
+            TokenType::Break => InstructionType::Break,
+            TokenType::Continue => InstructionType::Continue,
             TokenType::Load(i) => InstructionType::Load(*i),
             TokenType::Store(i) => InstructionType::Store(*i),
             TokenType::Syscall(i) => InstructionType::Syscall(*i),
-            TokenType::Identifier(identifier) => InstructionType::Identifier(identifier.to_string()),
+            TokenType::Identifier(identifier) => {
+                if let Some(binding) = self.find_binding(identifier) {
+                    InstructionType::Binding(binding)
+                } else if let Some(index) = self.inline_parameters.iter().position(|p| p == identifier) {
+                    InstructionType::Argument(identifier.clone(), index)
+                } else if self.peek_token().is_ok_and(|t| t.token == TokenType::OpenParen) {
+                    MacroCall::parse(self, identifier.clone())?
+                } else {
+                    InstructionType::Identifier(identifier.to_string())
+                }
+            }
             TokenType::While => While::parse(self)?,
+            TokenType::For => For::parse(self)?,
+            TokenType::Index => InstructionType::Index,
+            TokenType::Let => Let::parse(self)?,
+            TokenType::Match => Match::parse(self)?,
+            TokenType::Assert => InstructionType::Assert,
+            TokenType::Asm => Asm::parse(self)?,
+            TokenType::Panic => {
+                let TokenType::PushStr(message, _) = &self.next_token()?.token else {
+                    throw_exception_span(&span, "Use panic as: panic \"<message>\". The message should be a string literal".to_string());
+                    unreachable!();
+                };
+                InstructionType::Panic(message.clone())
+            }
+            TokenType::Case => {
+                throw_exception_span(&span, "'case' can only be used inside of: match <value> case <constant> do <block> end".to_string());
+                unreachable!();
+            }
+            TokenType::In => {
+                throw_exception_span(&span, "'in' can only be used in: let <identifiers> in <block> end".to_string());
+                unreachable!();
+            }
             TokenType::If => If::parse(self)?,
             TokenType::Elif => unreachable!("Should not encouter ELIF here",),
             TokenType::Else => unreachable!("Should not encounter ELSE here"),
@@ -401,13 +1195,98 @@ impl Parser {
             TokenType::Memory => unreachable!("Should not encounter MEMORY here"),
             TokenType::Procedure => unreachable!("Should not encounter PROC here"),
             TokenType::Inline => unreachable!("Should not encounter INLINE here"),
+            TokenType::Data => unreachable!("Should not encounter DATA here"),
+            TokenType::Struct => unreachable!("Should not encounter STRUCT here"),
+            TokenType::Enum => unreachable!("Should not encounter ENUM here"),
+            TokenType::Extern => unreachable!("Should not encounter EXTERN here"),
+            TokenType::Export => unreachable!("Should not encounter EXPORT here"),
+            TokenType::StaticAssert => {
+                throw_exception_span(&span, "'static_assert' can only be used on toplevel".to_string());
+                unreachable!();
+            }
+            TokenType::DirectiveIf | TokenType::DirectiveElif | TokenType::DirectiveElse | TokenType::DirectiveEnd => {
+                unreachable!("Should not encounter preprocessor directives here")
+            }
+            TokenType::OpenParen | TokenType::CloseParen | TokenType::Comma => {
+                throw_exception_span(&span, "Parentheses and commas can only be used for the parameters and arguments of an inline: name(a, b)".to_string());
+                unreachable!();
+            }
         };
 
         Ok(Instruction {
             instruction_type,
+            span,
         })
     }
 
+    /// Exits with an error when the identifier is already used by a procedure, inline, memory, data or extern
+    fn check_identifier_available(&mut self, identifier: &String) {
+        let span = self.current_token().unwrap().span.clone();
+        self.check_identifier_available_at(identifier, &span);
+    }
+
+    fn check_identifier_available_at(&mut self, identifier: &String, span: &Span) {
+        if self.replace_declarations {
+            self.forget(identifier);
+        } else if self.procedures_identifiers.contains(identifier) {
+            throw_exception_span(span, format!("'{}', is already a procedure name", identifier));
+        } else if self.inline_statements.contains(identifier) {
+            throw_exception_span(span, format!("'{}', is already an inline name", identifier));
+        } else if self.memories.contains(identifier) {
+            throw_exception_span(span, format!("'{}', is already a memory", identifier));
+        } else if self.datas.contains(identifier) {
+            throw_exception_span(span, format!("'{}', is already a data name", identifier));
+        } else if self.externs.contains(identifier) {
+            throw_exception_span(span, format!("'{}', is already an extern procedure", identifier));
+        }
+    }
+
+    /// Removes the declaration with this name, so it can be declared again
+    fn forget(&mut self, identifier: &String) {
+        self.procedures_identifiers.remove(identifier);
+        self.inline_statements.remove(identifier);
+        self.memories.remove(identifier);
+        self.datas.remove(identifier);
+        self.externs.remove(identifier);
+        self.program.procedures.remove(identifier);
+        self.program.inlines.remove(identifier);
+        self.program.memories.remove(identifier);
+        self.program.datas.remove(identifier);
+        self.program.externs.remove(identifier);
+    }
+
+    /// Parses the names of a stack effect `<inputs> -- <outputs>` up to the `end` token, which is not skipped
+    fn parse_stack_effect(&mut self, usage: &str, end: TokenType) -> Result<StackEffect, ()> {
+        let mut inputs = Vec::new();
+        let mut outputs = None;
+        while !self.current_token_is(end.clone()) {
+            let token = self.current_token()?.clone();
+            let minus = TokenType::InfixOperators(InfixOperators::Minus);
+            if token.token == minus && outputs.is_none() {
+                // The lexer sees `--` as two minus operators
+                if self.next_token()?.token != minus {
+                    throw_exception_span(&token.span, format!("{}. Separate the inputs and outputs with \"--\"", usage));
+                }
+                outputs = Some(Vec::new());
+            } else if let Some(word) = token.token.word() {
+                outputs.as_mut().unwrap_or(&mut inputs).push(word);
+            } else {
+                throw_exception_span(&token.span, format!("{}. \"{}\" can not be used as a name in a stack effect", usage, token.token));
+            }
+            self.next_token()?;
+        }
+
+        let Some(outputs) = outputs else {
+            throw_exception_span(&self.current_token()?.span, format!("{}. You forgot the \"--\" between the inputs and outputs", usage));
+            unreachable!();
+        };
+        Ok(StackEffect { inputs, outputs })
+    }
+
+    fn find_binding(&self, identifier: &String) -> Option<Binding> {
+        self.let_scopes.iter().rev().flatten().find(|b| b.identifier == *identifier).cloned()
+    }
+
     fn current_token(&self) -> Result<&Token, ()> {
         if self.tokens.get(self.cursor).is_some() {
             Ok(self.tokens.get(self.cursor).unwrap())
@@ -429,11 +1308,11 @@ impl Parser {
     //     self.peek_token().is_ok() && self.peek_token().unwrap().token == tokentype
     // }
 
-    // fn peek_token(&self) -> Result<&Token, ()> {
-    //     if self.tokens.get(self.cursor + 1).is_some() {
-    //         Ok(self.tokens.get(self.cursor + 1).unwrap())
-    //     } else {
-    //         Err(())
-    //     }
-    // }
+    fn peek_token(&self) -> Result<&Token, ()> {
+        if self.tokens.get(self.cursor + 1).is_some() {
+            Ok(self.tokens.get(self.cursor + 1).unwrap())
+        } else {
+            Err(())
+        }
+    }
 }
@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+
+use crate::compiler::CompilerOptions;
+use crate::operators::InfixOperators;
+use crate::throw_exception_span;
+use crate::tokens::{Token, TokenType};
+
+/// Flags every build defines, `-D NAME=value` on the command line can add or override them
+pub fn defines(options: &CompilerOptions) -> HashMap<String, String> {
+    let mut defines = HashMap::from([
+        (String::from("OS"), String::from("linux")),
+        (String::from("ARCH"), String::from("x86_64")),
+        (String::from("RELEASE"), String::from(if options.release { "1" } else { "0" })),
+    ]);
+    defines.extend(options.defines.iter().cloned());
+    defines
+}
+
+/// Removes the tokens of the branches of `#if`/`#elif`/`#else`/`#end` that are not taken, together with
+/// the directives themselves. Directives can be used anywhere, on toplevel as well as inside of blocks.
+///
+/// Conditions:
+/// - `#if NAME` is taken when the flag is defined and is not `0`
+/// - `#if NAME = value` and `#if NAME != value` compare the value of the flag
+pub fn process(tokens: Vec<Token>, defines: &HashMap<String, String>) -> Vec<Token> {
+    let mut preprocessor = Preprocessor { tokens, cursor: 0, defines, output: Vec::new() };
+    preprocessor.process_tokens(true);
+
+    if let Some(token) = preprocessor.tokens.get(preprocessor.cursor) {
+        throw_exception_span(&token.span, format!("'{}' without a matching '#if'", token.token));
+    }
+    preprocessor.output
+}
+
+struct Preprocessor<'a> {
+    tokens: Vec<Token>,
+    cursor: usize,
+    defines: &'a HashMap<String, String>,
+    output: Vec<Token>,
+}
+
+impl Preprocessor<'_> {
+    /// Copies tokens to the output (when `active`) until an `#elif`, `#else` or `#end` that is not
+    /// part of a nested `#if`, the cursor is left on that directive.
+    fn process_tokens(&mut self, active: bool) {
+        while let Some(token) = self.tokens.get(self.cursor) {
+            match token.token {
+                TokenType::DirectiveIf => self.process_if(active),
+                TokenType::DirectiveElif | TokenType::DirectiveElse | TokenType::DirectiveEnd => return,
+                _ => {
+                    if active {
+                        self.output.push(token.clone());
+                    }
+                    self.cursor += 1;
+                }
+            }
+        }
+    }
+
+    fn process_if(&mut self, active: bool) {
+        let span = self.tokens[self.cursor].span.clone();
+        let mut taken = false; // Only the first branch with a true condition is taken
+
+        loop {
+            let directive = self.tokens[self.cursor].token.clone();
+            let condition = match directive {
+                TokenType::DirectiveIf | TokenType::DirectiveElif => self.condition(),
+                _ => {
+                    self.cursor += 1;
+                    true
+                }
+            };
+
+            self.process_tokens(active && !taken && condition);
+            taken |= condition;
+
+            let Some(token) = self.tokens.get(self.cursor) else {
+                throw_exception_span(&span, "This '#if' is never closed, end it with '#end'".to_string());
+                unreachable!();
+            };
+            match token.token {
+                TokenType::DirectiveEnd => break,
+                TokenType::DirectiveElif | TokenType::DirectiveElse if directive == TokenType::DirectiveElse => {
+                    throw_exception_span(&token.span, format!("'{}' can not follow '#else'", token.token));
+                }
+                _ => (),
+            }
+        }
+        self.cursor += 1; // Skipping over the #END token
+    }
+
+    /// Parses and evaluates the condition following `#if` or `#elif`, moving the cursor past it
+    fn condition(&mut self) -> bool {
+        let directive = self.tokens[self.cursor].clone();
+        self.cursor += 1;
+
+        let name = self.word(&directive);
+        let comparison = match self.tokens.get(self.cursor).map(|t| &t.token) {
+            Some(TokenType::InfixOperators(InfixOperators::Equals)) => Some(true),
+            Some(TokenType::InfixOperators(InfixOperators::NotEquals)) => Some(false),
+            _ => None,
+        };
+
+        let value = self.defines.get(&name).cloned();
+        let Some(equals) = comparison else {
+            return value.is_some_and(|v| v != "0");
+        };
+        self.cursor += 1; // Skipping over the operator
+        let expected = self.word(&directive);
+        (value == Some(expected)) == equals
+    }
+
+    /// Returns the current token as a flag name or value and moves past it
+    fn word(&mut self, directive: &Token) -> String {
+        let Some(token) = self.tokens.get(self.cursor) else {
+            throw_exception_span(&directive.span, format!("Write the condition as: {0} NAME, {0} NAME = value or {0} NAME != value", directive.token));
+            unreachable!();
+        };
+        let word = match &token.token {
+            TokenType::PushInt(int) => int.to_string(),
+            other => other.word().unwrap_or_else(|| {
+                throw_exception_span(&token.span, format!("\"{}\" can not be used in the condition of '{}'", other, directive.token));
+                unreachable!();
+            }),
+        };
+        self.cursor += 1;
+        word
+    }
+}
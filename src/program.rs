@@ -1,20 +1,28 @@
 use std::collections::HashMap;
 
-use crate::parser::{Procedure, Inline, Memory};
+use crate::parser::{Procedure, Inline, Memory, Data, Extern};
 
 #[derive(Debug, Clone, PartialEq)]
 pub struct Program {
     pub procedures: HashMap<String, Procedure>,
     pub inlines: HashMap<String, Inline>,
     pub memories: HashMap<String, Memory>,
+    pub datas: HashMap<String, Data>,
+    pub externs: HashMap<String, Extern>,
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Program {
     pub fn new() -> Program {
-        Program { procedures: HashMap::new(), inlines: HashMap::new(), memories: HashMap::new() }
+        Program { procedures: HashMap::new(), inlines: HashMap::new(), memories: HashMap::new(), datas: HashMap::new(), externs: HashMap::new() }
     }
 
-    pub fn from(procedures: HashMap<String, Procedure>, inlines: HashMap<String, Inline>, memories: HashMap<String, Memory>) -> Program {
-        Program { procedures, inlines, memories }
+    pub fn from(procedures: HashMap<String, Procedure>, inlines: HashMap<String, Inline>, memories: HashMap<String, Memory>, datas: HashMap<String, Data>, externs: HashMap<String, Extern>) -> Program {
+        Program { procedures, inlines, memories, datas, externs }
     }
 }
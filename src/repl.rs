@@ -0,0 +1,264 @@
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::panic::AssertUnwindSafe;
+use std::path::PathBuf;
+
+use crate::checker;
+use crate::compiler::CompilerOptions;
+use crate::interpreter::{self, Machine, Stop};
+use crate::lexer::Lexer;
+use crate::parser::Parser;
+use crate::preprocessor;
+use crate::tokens::{Token, TokenType};
+
+const HELP: &str = "\
+Lines are executed as soon as they are entered, the stack is shown after every line.
+Declarations (proc, inline, memory, data, struct, enum, ...) are added to the program and can be
+used by the following lines. Input continues on the next line until every block is closed.
+
+COMMANDS:
+\t:load <path>   Adds the declarations of a file
+\t:clear         Empties the stack
+\t:history       Lists the previous inputs
+\t!N             Runs input N of the history again, !! runs the last one
+\t:help          Shows this help
+\t:quit          Leaves the REPL, like the end of the input (Ctrl-D)";
+
+/// Reads, evaluates and prints until the input ends or the program exits, returns the exit code
+pub fn run(options: CompilerOptions) -> i32 {
+    crate::recover_from_errors();
+    // Internal errors of the compiler are reported like any other error instead of ending the REPL
+    std::panic::set_hook(Box::new(|info| {
+        let message = info.payload().downcast_ref::<&str>().map(|m| m.to_string()).or_else(|| info.payload().downcast_ref::<String>().cloned());
+        println!("Error: {}", message.unwrap_or_else(|| String::from("the input could not be compiled")));
+    }));
+
+    let mut repl = Repl {
+        parser: Parser::new(Vec::new()),
+        machine: Machine::new(options.clone(), &[String::from("repl")]),
+        options,
+        history: Vec::new(),
+        history_path: std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".stapel_history")),
+    };
+    repl.load_history();
+
+    println!("Stapel REPL, type :help for help");
+    let stdin = std::io::stdin();
+    let mut lines = stdin.lock().lines();
+    'repl: loop {
+        let Some(mut input) = read_line(&mut lines, "> ") else {
+            return 0;
+        };
+
+        // Blocks can span several lines
+        if !input.trim_start().starts_with([':', '!']) {
+            loop {
+                match open_blocks(&input) {
+                    Some(open) if open > 0 => (),
+                    Some(_) => break,
+                    // The lexer reported the error
+                    None => continue 'repl,
+                }
+                let Some(line) = read_line(&mut lines, "... ") else {
+                    return 0;
+                };
+                input.push('\n');
+                input.push_str(&line);
+            }
+        }
+
+        let input = input.trim().to_string();
+        let result = match input.as_str() {
+            "" => continue,
+            ":quit" | ":q" => return 0,
+            ":help" => {
+                println!("{}", HELP);
+                continue;
+            }
+            ":clear" => {
+                repl.machine.stack.clear();
+                repl.print_stack();
+                continue;
+            }
+            ":history" => {
+                for (index, entry) in repl.history.iter().enumerate() {
+                    println!("{:>4}  {}", index + 1, entry.replace('\n', "\n      "));
+                }
+                continue;
+            }
+            load if load.starts_with(":load") => {
+                let path = load[5..].trim();
+                let Ok(source) = std::fs::read_to_string(path) else {
+                    println!("Could not read file at location: '{}'", path);
+                    continue;
+                };
+                repl.add_history(&input);
+                repl.evaluate(source, path.to_string())
+            }
+            command if command.starts_with(':') => {
+                println!("'{}' is not a command, type :help for help", command);
+                continue;
+            }
+            recall if recall.starts_with('!') => {
+                let entry = match &recall[1..] {
+                    "!" => repl.history.last(),
+                    number => number.parse::<usize>().ok().and_then(|n| n.checked_sub(1)).and_then(|n| repl.history.get(n)),
+                };
+                let Some(entry) = entry.cloned() else {
+                    println!("'{}' is not in the history, list it with :history", recall);
+                    continue;
+                };
+                println!("{}", entry);
+                repl.add_history(&entry);
+                let name = format!("<{}>", repl.history.len());
+                repl.evaluate(entry, name)
+            }
+            _ => {
+                repl.add_history(&input);
+                let name = format!("<{}>", repl.history.len());
+                repl.evaluate(input, name)
+            }
+        };
+
+        if let Err(Stop::Exit(code)) = result {
+            return code as i32;
+        }
+        repl.print_stack();
+    }
+}
+
+struct Repl {
+    /// Holds the program defined so far
+    parser: Parser,
+    machine: Machine,
+    options: CompilerOptions,
+    history: Vec<String>,
+    history_path: Option<PathBuf>,
+}
+
+impl Repl {
+    /// Adds the declarations of the source to the program and executes its instructions. The
+    /// program is left unchanged when the source has errors.
+    fn evaluate(&mut self, source: String, name: String) -> Result<(), Stop> {
+        let snapshot = self.parser.clone();
+        let (parser, options) = (&mut self.parser, &self.options);
+        let parsed = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            let mut l = Lexer::new(source, name);
+            l.tokenize();
+            let tokens = preprocessor::process(l.tokens, &preprocessor::defines(options));
+            let block = parser.parse_input(tokens);
+            checker::check(&parser.program, options);
+            interpreter::resolve(&parser.program, Some(&block));
+            block
+        }));
+        let Ok(block) = parsed else {
+            self.parser = snapshot;
+            return Err(Stop::Error);
+        };
+
+        // A memory or data that was declared again gets new storage when it is used next
+        let (before, after) = (&snapshot.program, &self.parser.program);
+        for identifier in before.memories.keys().chain(before.datas.keys()) {
+            if before.memories.get(identifier) != after.memories.get(identifier) || before.datas.get(identifier) != after.datas.get(identifier) {
+                self.machine.forget(identifier);
+            }
+        }
+        if block.instructions.is_empty() {
+            return Ok(());
+        }
+
+        let (program, machine) = (&self.parser.program, &mut self.machine);
+        // An internal error of the interpreter does not end the REPL
+        match std::panic::catch_unwind(AssertUnwindSafe(|| interpreter::execute(program, machine, &block))) {
+            Ok(result) => result,
+            Err(_) => {
+                self.machine.flush();
+                Err(Stop::Error)
+            }
+        }
+    }
+
+    fn print_stack(&self) {
+        let values: Vec<String> = self.machine.stack.iter().map(|value| value.to_string()).collect();
+        if values.is_empty() {
+            println!("[ ]");
+        } else {
+            println!("[ {} ]", values.join(" "));
+        }
+    }
+
+    /// Reads the history of earlier sessions, every entry is a line with its newlines escaped
+    fn load_history(&mut self) {
+        let Some(source) = self.history_path.as_ref().and_then(|path| std::fs::read_to_string(path).ok()) else {
+            return;
+        };
+        self.history = source.lines().map(unescape).collect();
+    }
+
+    fn add_history(&mut self, entry: &str) {
+        self.history.push(entry.to_string());
+        let Some(path) = &self.history_path else {
+            return;
+        };
+        if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+            let _ = writeln!(file, "{}", entry.replace('\\', "\\\\").replace('\n', "\\n"));
+        }
+    }
+}
+
+fn unescape(line: &str) -> String {
+    let mut entry = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                entry.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                entry.push('\\');
+                chars.next();
+            }
+            _ => entry.push(c),
+        }
+    }
+    entry
+}
+
+/// Shows the prompt and reads a line, None at the end of the input
+fn read_line(lines: &mut impl Iterator<Item = std::io::Result<String>>, prompt: &str) -> Option<String> {
+    print!("{}", prompt);
+    let _ = std::io::stdout().flush();
+    match lines.next() {
+        Some(Ok(line)) => Some(line),
+        _ => {
+            println!();
+            None
+        }
+    }
+}
+
+/// Blocks the input opens without closing them with `end`, None when it can not be lexed
+fn open_blocks(input: &str) -> Option<i64> {
+    let mut l = Lexer::new(input.to_string(), String::from("<input>"));
+    let tokens = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        l.tokenize();
+        l.tokens
+    })).ok()?;
+
+    let mut open = 0;
+    let mut previous: Option<&Token> = None;
+    for token in &tokens {
+        open += match token.token {
+            // `extern proc` is closed by a single end
+            TokenType::Procedure if previous.is_some_and(|previous| previous.token == TokenType::Extern) => 0,
+            TokenType::While | TokenType::For | TokenType::Let | TokenType::Match | TokenType::If | TokenType::Asm | TokenType::StaticAssert => 1,
+            TokenType::Procedure | TokenType::Inline | TokenType::Memory | TokenType::Data | TokenType::Struct | TokenType::Enum | TokenType::Extern => 1,
+            TokenType::DirectiveIf => 1,
+            TokenType::End | TokenType::DirectiveEnd => -1,
+            _ => 0,
+        };
+        previous = Some(token);
+    }
+    Some(open)
+}
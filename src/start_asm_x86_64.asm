@@ -1,16 +1,72 @@
 ; X86_64 Assembly linux (Ubuntu 22.04)
 ; NASM version 2.15.05
+%ifndef LIBRARY
 global _start
+%endif
+
+%define AUX_STACK_SIZE 8192 ; Entries of the auxiliary stack, holds the state of for loops
+%define BACKTRACE_LIMIT 64  ; Most procedures a backtrace shows
+%ifndef RET_STACK_SIZE
+%define RET_STACK_SIZE 1024 ; Entries of the return stack, the initial amount when RET_STACK_MAX is defined
+%endif
+; When RET_STACK_MAX is defined, the return stack is mapped with mmap and doubles in size up to that amount
+
+%ifndef DATA_STACK_LIMIT
+%define DATA_STACK_LIMIT 524288 ; Values the data stack can hold in --checked builds and with NATIVE_CALLS
+%endif
+%define SIGNAL_STACK_SIZE 65536 ; Bytes of the stack signal handlers run on, so a stack overflow can be reported
+; When NATIVE_CALLS is defined, procedures are called with call/ret on the hardware stack and the data
+; stack is mapped with mmap, RBP points to its top. Otherwise RSP is the data stack and calls use call_proxy.
+; When LIBRARY is defined, there is no _start, the wrappers of exported procedures set up the stacks.
+; When LINK_LIBC is defined, runtime errors exit through libc's exit, so the buffers of stdio are flushed.
+
+;; Data stack access for asm blocks: dpush <operand> and dpop <register>
+%ifdef NATIVE_CALLS
+%macro dpush 1
+    lea rbp, [rbp - 8]
+    mov QWORD [rbp], %1
+%endmacro
+%macro dpop 1
+    mov %1, [rbp]
+    lea rbp, [rbp + 8]
+%endmacro
+%else
+%macro dpush 1
+    push %1
+%endmacro
+%macro dpop 1
+    pop %1
+%endmacro
+%endif
 
 section .bss
     ; Reserve space for the global variables
     argc: resq 1   ; 64-bit integer
     argv: resq 1   ; 64-bit pointer
+    aux_stack: resq AUX_STACK_SIZE ; Auxiliary stack, R15 is the index of the first free entry
+    signal_stack: resb SIGNAL_STACK_SIZE
+%ifdef LINK_LIBC
+    faulted: resb 1 ; Set by the signal handlers, libc may be in any state after a fault
+%endif
+%ifdef LIBRARY
+%ifdef NATIVE_CALLS
+    data_stack_top: resq 1 ; Top of the data stack of the exported procedures, mapped by the first call
+%endif
+%endif
+%ifdef RET_STACK_MAX
+    ret_stack_ptr: resq 1      ; Address of the mapped return stack
+    ret_stack_capacity: resq 1 ; Entries of the mapped return stack
+%else
+    ret_stack: resq RET_STACK_SIZE ; Stack for the return adresses, R13 is the index of the top entry
+%endif
 
 section .data
     ori_stack_ptr: dq 0 ; Pointer to start of stack
-    ret_stack: TIMES 1024 DQ 0; Stack for the return adresses
+    data_stack_limit: dq 0 ; Lowest address the data stack may grow to in --checked builds
     ret_stack_cursor: DQ 0; Pointer to start of memory
+%ifdef NATIVE_CALLS
+    call_stack_top: dq 0 ; Hardware stack pointer before main was called, where backtraces stop
+%endif
 
     overflow_msg: db "Error: Shadow Stack Overflow!", 10
     overflow_len: equ $ - overflow_msg
@@ -18,6 +74,31 @@ section .data
     underflow_msg: db "Error: Shadow Stack Underflow!", 10
     underflow_len: equ $ - underflow_msg
 
+    aux_overflow_msg: db "Error: Auxiliary Stack Overflow!", 10
+    aux_overflow_len: equ $ - aux_overflow_msg
+
+    literal_write_msg: db "Error: Attempted to write into a string literal!", 10
+    literal_write_len: equ $ - literal_write_msg
+
+    segfault_msg: db "Error: Segmentation fault!", 10
+    segfault_len: equ $ - segfault_msg
+
+    arithmetic_msg: db "Error: Division by zero or signed overflow of a division!", 10
+    arithmetic_len: equ $ - arithmetic_msg
+
+    backtrace_msg: db "Backtrace (most recent call first):", 10
+    backtrace_len: equ $ - backtrace_msg
+
+    backtrace_cut_msg: db 9, "...", 10
+    backtrace_cut_len: equ $ - backtrace_cut_msg
+
+    ; struct sigaction for SIGSEGV: handler, flags (SA_SIGINFO | SA_RESTORER | SA_ONSTACK), restorer, mask
+    segv_action: dq segv_handler, 0x0C000004, segv_restorer, 0
+    ; struct sigaction for SIGFPE, raised by idiv
+    fpe_action: dq fpe_handler, 0x0C000004, segv_restorer, 0
+    ; stack_t for sigaltstack: pointer, flags, size
+    signal_stack_desc: dq signal_stack, 0, SIGNAL_STACK_SIZE
+
 section .text
 print_i64:
     sub     rsp, 40
@@ -63,43 +144,332 @@ call_proxy:
     ;pop rax            ; Get return address from hardware stack
     ;pop rdi            ; Get target procedure address from hardware stack
 
-    ; --- Safety Check ---
-    cmp r13, 1023      ; Check if we are at the limit of ret_stack (1024 entries)
-    jge stack_overflow ; If r13 >= 1023, trigger error
-
     ; Use R13 as the index. 
     ; We scale by 8 because these are 64-bit (8-byte) addresses.
+%ifdef RET_STACK_MAX
+    inc r13                             ; Move to next slot
+    cmp r13, [ret_stack_capacity]       ; Grow the return stack when it is full
+    jb .store
+    call grow_ret_stack
+.store:
+    mov rcx, [ret_stack_ptr]
+    mov [rcx + r13 * 8], rax            ; Store return address
+%else
+    ; --- Safety Check ---
+    cmp r13, RET_STACK_SIZE - 1 ; Check if we are at the limit of ret_stack
+    jge stack_overflow          ; If r13 >= RET_STACK_SIZE - 1, trigger error
+
     inc r13                             ; Move to next slot
     mov [ret_stack + r13 * 8], rax      ; Store return address
+%endif
 
     jmp rdi            ; Jump to procedure
 
+%ifdef RET_STACK_MAX
+grow_ret_stack:
+    ; Doubles the entries of the return stack with mremap, up to RET_STACK_MAX entries.
+    ; Keeps rax (return address) and rdi (target procedure) of call_proxy.
+    push rax
+    push rdi
+    mov rsi, [ret_stack_capacity]
+    cmp rsi, RET_STACK_MAX
+    jae .overflow
+    lea r8, [rsi * 2]
+    cmp r8, RET_STACK_MAX
+    jbe .remap
+    mov r8, RET_STACK_MAX
+.remap:
+    mov rax, 25         ; sys_mremap
+    mov rdi, [ret_stack_ptr]
+    shl rsi, 3          ; Old size in bytes
+    lea rdx, [r8 * 8]   ; New size in bytes
+    mov r10, 1          ; MREMAP_MAYMOVE
+    syscall
+    cmp rax, -4095      ; Errors are returned as -errno
+    jae .overflow
+
+    mov [ret_stack_ptr], rax
+    mov [ret_stack_capacity], r8
+    pop rdi
+    pop rax
+    ret
+.overflow:
+    pop rdi
+    pop rax
+    dec r13             ; The slot call_proxy moved to does not exist
+    jmp stack_overflow
+%endif
+
 stack_overflow:
+    mov rbx, rax        ; The return address, inside of the calling procedure
     mov rax, 1          ; sys_write
     mov rdi, 2          ; stderr
     mov rsi, overflow_msg
     mov rdx, overflow_len
     syscall
-
-    mov rax, 60        ; sys_exit
-    mov rdi, 1         ; error code 1
-    syscall
+    jmp exit_with_backtrace
 
 stack_underflow:
+    xor rbx, rbx        ; Address of the error is unknown
     mov rax, 1          ; sys_write
     mov rdi, 2          ; stderr
     mov rsi, underflow_msg
     mov rdx, underflow_len
     syscall
+    jmp exit_with_backtrace
+
+aux_stack_overflow:
+    xor rbx, rbx        ; Address of the error is unknown
+    mov rax, 1          ; sys_write
+    mov rdi, 2          ; stderr
+    mov rsi, aux_overflow_msg
+    mov rdx, aux_overflow_len
+    syscall
+    jmp exit_with_backtrace
 
+runtime_error:
+    ; rsi points to the message, rdx holds its length, rbx holds the address of the error
+    mov rax, 1          ; sys_write
+    mov rdi, 2          ; stderr
+    syscall
+
+exit_with_backtrace:
+%ifdef NATIVE_CALLS
+    mov r14, rsp       ; The return addresses of the procedures are on top of the hardware stack
+%endif
+exit_with_backtrace_at:
+    ; Signal handlers jump here directly, with r14 set to the stack pointer at the fault
+    call print_backtrace
+
+%ifdef LINK_LIBC
+    cmp byte [faulted], 0
+    jne .sys_exit
+    mov edi, 1         ; error code 1
+    and rsp, -16
+    call exit
+.sys_exit:
+%endif
     mov rax, 60        ; sys_exit
     mov rdi, 1         ; error code 1
     syscall
 
+print_backtrace:
+    ; Prints the procedure containing rbx (skipped when 0), followed by the procedures of the
+    ; return addresses on ret_stack, or on the hardware stack from r14 up with NATIVE_CALLS.
+    ; Only used right before exiting, so clobbers r13 and r14.
+    mov rax, 1          ; sys_write
+    mov rdi, 2          ; stderr
+    mov rsi, backtrace_msg
+    mov rdx, backtrace_len
+    syscall
+
+    mov r12, BACKTRACE_LIMIT
+    test rbx, rbx
+    jz .frames
+    mov rdi, rbx
+    call print_proc_location
+    dec r12
+.frames:
+%ifdef NATIVE_CALLS
+    cmp r14, [call_stack_top]
+    jae .done
+    test r12, r12
+    jz .cut
+    mov rdi, [r14]
+    call print_proc_location
+    add r14, 8
+    dec r12
+    jmp .frames
+%else
+    test r13, r13
+    jz .done
+    test r12, r12
+    jz .cut
+%ifdef RET_STACK_MAX
+    mov rdi, [ret_stack_ptr]
+    mov rdi, [rdi + r13 * 8]
+%else
+    mov rdi, [ret_stack + r13 * 8]
+%endif
+    call print_proc_location
+    dec r13
+    dec r12
+    jmp .frames
+%endif
+.cut:
+    mov rax, 1          ; sys_write
+    mov rdi, 2          ; stderr
+    mov rsi, backtrace_cut_msg
+    mov rdx, backtrace_cut_len
+    syscall
+.done:
+    ret
+
+print_proc_location:
+    ; rdi holds a code address, prints the line of the procedure containing it from proc_table
+    ; (entries of: start, end, line address, line length)
+    mov rsi, proc_table
+.search:
+    cmp rsi, proc_table_end
+    jae .unknown
+    cmp rdi, [rsi]
+    jb .next
+    cmp rdi, [rsi + 8]
+    jae .next
+
+    mov rdx, [rsi + 24]
+    mov rsi, [rsi + 16]
+    mov rax, 1          ; sys_write
+    mov rdi, 2          ; stderr
+    syscall
+    ret
+.next:
+    add rsi, 32
+    jmp .search
+.unknown:
+    ret
+
+segv_handler:
+    ; rsi points to the siginfo_t, si_addr (the faulting address) is at offset 16
+    ; rdx points to the ucontext_t, the instruction pointer of the fault is at offset 168
+    mov rbx, [rdx + 168]
+    mov r12, rdx
+    mov rax, [rsi + 16]
+    cmp rax, strings_start
+    jb .not_literal
+    cmp rax, strings_end
+    jae .not_literal
+
+    mov rax, 1          ; sys_write
+    mov rdi, 2          ; stderr
+    mov rsi, literal_write_msg
+    mov rdx, literal_write_len
+    syscall
+    jmp .exit
+.not_literal:
+    mov rax, 1          ; sys_write
+    mov rdi, 2          ; stderr
+    mov rsi, segfault_msg
+    mov rdx, segfault_len
+    syscall
+.exit:
+    jmp fault_exit
+
+fpe_handler:
+    ; rdx points to the ucontext_t, the instruction pointer of the fault is at offset 168
+    mov rbx, [rdx + 168]
+    mov r12, rdx
+    mov rax, 1          ; sys_write
+    mov rdi, 2          ; stderr
+    mov rsi, arithmetic_msg
+    mov rdx, arithmetic_len
+    syscall
+    jmp fault_exit
+
+fault_exit:
+    ; r12 points to the ucontext_t of the fault, the stack pointer of the fault is at offset 160
+%ifdef LINK_LIBC
+    mov byte [faulted], 1
+%endif
+%ifdef NATIVE_CALLS
+    mov r14, [r12 + 160]
+    jmp exit_with_backtrace_at
+%else
+    jmp exit_with_backtrace
+%endif
+
+segv_restorer:
+    ; Never reached as the handlers exit, but the kernel requires a restorer on x86_64
+    mov rax, 15        ; sys_rt_sigreturn
+    syscall
+
+%ifdef NATIVE_CALLS
+map_data_stack:
+    ; Maps the data stack and returns its top in rax. The lowest page is made inaccessible,
+    ; so overflowing the data stack faults. Clobbers rbx and the registers of syscalls.
+    mov rax, 9          ; sys_mmap
+    xor rdi, rdi        ; Any address
+    mov rsi, DATA_STACK_LIMIT * 8 + 4096
+    mov rdx, 3          ; PROT_READ | PROT_WRITE
+    mov r10, 0x22       ; MAP_PRIVATE | MAP_ANONYMOUS
+    mov r8, -1          ; No file
+    xor r9, r9
+    syscall
+    cmp rax, -4095      ; Errors are returned as -errno
+    jae stack_overflow
+    mov rbx, rax
+
+    mov rax, 10         ; sys_mprotect
+    mov rdi, rbx
+    mov rsi, 4096
+    xor rdx, rdx        ; PROT_NONE
+    syscall
+
+    mov rax, DATA_STACK_LIMIT * 8 + 4096
+    add rax, rbx
+    ret
+%endif
+
+%ifndef LIBRARY
 _start:
     ; DEFAULT INSTRUCTIONS
     mov [ori_stack_ptr], rsp
+    mov rax, rsp
+    mov rbx, DATA_STACK_LIMIT * 8
+    sub rax, rbx
+    mov [data_stack_limit], rax
     xor r13, r13
+    xor r15, r15
+
+%ifdef RET_STACK_MAX
+    ; --- MAP THE RETURN STACK ---
+    mov rax, 9          ; sys_mmap
+    xor rdi, rdi        ; Any address
+    mov rsi, RET_STACK_SIZE * 8
+    mov rdx, 3          ; PROT_READ | PROT_WRITE
+    mov r10, 0x22       ; MAP_PRIVATE | MAP_ANONYMOUS
+    mov r8, -1          ; No file
+    xor r9, r9
+    syscall
+    cmp rax, -4095      ; Errors are returned as -errno
+    jae stack_overflow
+    mov [ret_stack_ptr], rax
+    mov qword [ret_stack_capacity], RET_STACK_SIZE
+%endif
+
+%ifdef NATIVE_CALLS
+    ; --- MAP THE DATA STACK ---
+    call map_data_stack
+    mov rbp, rax
+    mov [ori_stack_ptr], rbp
+    sub rax, DATA_STACK_LIMIT * 8
+    mov [data_stack_limit], rax
+%endif
+
+    ; --- SIGNAL STACK ---
+    ; Handlers run on their own stack, otherwise a fault from a full stack can not be reported
+    mov rax, 131        ; sys_sigaltstack
+    mov rdi, signal_stack_desc
+    xor rsi, rsi        ; No old stack
+    syscall
+
+    ; --- INSTALL SIGSEGV HANDLER ---
+    ; String literals live in .rodata, so writing into one faults and is reported by segv_handler
+    mov rax, 13         ; sys_rt_sigaction
+    mov rdi, 11         ; SIGSEGV
+    mov rsi, segv_action
+    xor rdx, rdx        ; No old action
+    mov r10, 8          ; sizeof(sigset_t)
+    syscall
+
+    ; --- INSTALL SIGFPE HANDLER ---
+    ; Reports division by zero in builds without --checked-arith
+    mov rax, 13         ; sys_rt_sigaction
+    mov rdi, 8          ; SIGFPE
+    mov rsi, fpe_action
+    xor rdx, rdx        ; No old action
+    mov r10, 8          ; sizeof(sigset_t)
+    syscall
 
     ; --- CAPTURE ARGS ---
     mov rax, [rsp]      ; The top of the stack holds 'argc'
@@ -108,6 +478,13 @@ _start:
     lea rax, [rsp + 8]  ; The next item is the pointer to argv[0]
     mov [argv], rax     ; Save the *address* of the argv array
 
+%ifdef NATIVE_CALLS
+    mov [call_stack_top], rsp
+    call proc_main
+    jmp stack_underflow ; main returned, like returning with an empty ret_stack
+%else
     jmp proc_main
+%endif
+%endif ; LIBRARY
     
     ; ADDED COMPILED INSTRUCTIONS
@@ -37,6 +37,23 @@ pub enum TokenType {
     Pick,
     Put,
     While,
+    For,
+    Index,
+    Let,
+    In,
+    Match,
+    Case,
+    Assert,
+    Asm,
+    Panic,
+    StaticAssert,
+    OpenParen,
+    CloseParen,
+    Comma,
+    DirectiveIf,
+    DirectiveElif,
+    DirectiveElse,
+    DirectiveEnd,
     If,
     Elif,
     Else,
@@ -48,12 +65,69 @@ pub enum TokenType {
     Return,
     Procedure,
     Inline,
+    Data,
+    Struct,
+    Enum,
+    Extern,
+    Export,
+    Break,
+    Continue,
     Load(usize),
     Store(usize),
     Syscall(u8),
     Identifier(String),
 }
 
+impl TokenType {
+    /// The word an identifier or keyword was written as, so keywords can be used as names of
+    /// struct fields and enum variants. `end` is excluded as it closes those declarations.
+    pub fn word(&self) -> Option<String> {
+        let word = match self {
+            TokenType::Identifier(identifier) => identifier.as_str(),
+            TokenType::InfixOperators(InfixOperators::And) => "and",
+            TokenType::InfixOperators(InfixOperators::Or) => "or",
+            TokenType::Pop => "pop",
+            TokenType::Swap => "swap",
+            TokenType::Rot => "rot",
+            TokenType::Over => "over",
+            TokenType::Pick => "pick",
+            TokenType::Put => "put",
+            TokenType::While => "while",
+            TokenType::For => "for",
+            TokenType::Index => "i",
+            TokenType::Let => "let",
+            TokenType::In => "in",
+            TokenType::Match => "match",
+            TokenType::Case => "case",
+            TokenType::Assert => "assert",
+            TokenType::Asm => "asm",
+            TokenType::Panic => "panic",
+            TokenType::StaticAssert => "static_assert",
+            TokenType::If => "if",
+            TokenType::Elif => "elif",
+            TokenType::Else => "else",
+            TokenType::Do => "do",
+            TokenType::Dup => "dup",
+            TokenType::Size => "size",
+            TokenType::Memory => "memory",
+            TokenType::Return => "return",
+            TokenType::Procedure => "proc",
+            TokenType::Inline => "inline",
+            TokenType::Data => "data",
+            TokenType::Struct => "struct",
+            TokenType::Enum => "enum",
+            TokenType::Extern => "extern",
+            TokenType::Export => "export",
+            TokenType::Break => "break",
+            TokenType::Continue => "continue",
+            TokenType::Syscall(i) => return Some(format!("syscall{}", i)),
+            _ => return None,
+        };
+
+        Some(word.to_string())
+    }
+}
+
 impl std::fmt::Display for TokenType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         let value = match self {
@@ -67,6 +141,23 @@ impl std::fmt::Display for TokenType {
             TokenType::Pick => String::from("Pick"),
             TokenType::Put => String::from("Put"),
             TokenType::While => String::from("While"),
+            TokenType::For => String::from("For"),
+            TokenType::Index => String::from("Index"),
+            TokenType::Let => String::from("Let"),
+            TokenType::In => String::from("In"),
+            TokenType::Match => String::from("Match"),
+            TokenType::Case => String::from("Case"),
+            TokenType::Assert => String::from("Assert"),
+            TokenType::Asm => String::from("Asm"),
+            TokenType::Panic => String::from("Panic"),
+            TokenType::StaticAssert => String::from("StaticAssert"),
+            TokenType::OpenParen => String::from("OpenParen"),
+            TokenType::CloseParen => String::from("CloseParen"),
+            TokenType::Comma => String::from("Comma"),
+            TokenType::DirectiveIf => String::from("#if"),
+            TokenType::DirectiveElif => String::from("#elif"),
+            TokenType::DirectiveElse => String::from("#else"),
+            TokenType::DirectiveEnd => String::from("#end"),
             TokenType::If => String::from("If"),
             TokenType::Elif => String::from("Elif"),
             TokenType::Else => String::from("Else"),
@@ -78,6 +169,13 @@ impl std::fmt::Display for TokenType {
             TokenType::Procedure => String::from("Procedure"),
             TokenType::Return => String::from("Return"),
             TokenType::Inline => String::from("Inline"),
+            TokenType::Data => String::from("Data"),
+            TokenType::Struct => String::from("Struct"),
+            TokenType::Enum => String::from("Enum"),
+            TokenType::Extern => String::from("Extern"),
+            TokenType::Export => String::from("Export"),
+            TokenType::Break => String::from("Break"),
+            TokenType::Continue => String::from("Continue"),
             TokenType::Load(_) => String::from("Load"),
             TokenType::Store(_) => String::from("Store"),
             TokenType::Syscall(i) => format!("Syscall{}", i),
@@ -0,0 +1,307 @@
+use crate::bytecode::{Bytecode, Op, Static, StaticValue};
+use crate::interpreter::{Machine, Stop};
+use crate::parser::DataValue;
+use crate::tokens::Span;
+
+/// Executes bytecode from its entry point, returns the exit code of the program
+pub fn exec(bytecode: &Bytecode, machine: &mut Machine) -> i32 {
+    let result = Vm::new(bytecode, machine).and_then(|mut vm| vm.run());
+    machine.flush();
+
+    match result {
+        Ok(()) => 0,
+        Err(Stop::Exit(code)) => code as i32,
+        Err(Stop::Error) => 1,
+    }
+}
+
+struct Frame {
+    /// Instruction after the call
+    return_pc: usize,
+    /// Lengths of the binding and loop stacks at the call, restored when the call returns
+    bindings: usize,
+    loops: usize,
+}
+
+struct Vm<'a> {
+    bytecode: &'a Bytecode,
+    machine: &'a mut Machine,
+    pc: usize,
+    frames: Vec<Frame>,
+    bindings: Vec<i64>,
+    /// (index, end) of the for loops being executed
+    loops: Vec<(i64, i64)>,
+    /// Addresses of the strings, set when a string is first pushed
+    strings: Vec<Option<i64>>,
+    statics: Vec<i64>,
+}
+
+impl<'a> Vm<'a> {
+    /// Allocates the memories and data of the program
+    fn new(bytecode: &'a Bytecode, machine: &'a mut Machine) -> Result<Vm<'a>, Stop> {
+        let mut vm = Vm { bytecode, machine, pc: bytecode.entry, frames: Vec::new(), bindings: Vec::new(), loops: Vec::new(), strings: vec![None; bytecode.strings.len()], statics: Vec::new() };
+
+        for stat in &bytecode.statics {
+            let allocation = match stat {
+                Static::Memory(size) => vm.machine.allocate_memory(*size),
+                Static::Argc => Ok(vm.machine.argument_address("argc")),
+                Static::Argv => Ok(vm.machine.argument_address("argv")),
+                Static::Data { width, values } => {
+                    let values: Vec<DataValue> = values.iter().map(|value| match value {
+                        StaticValue::Int(i) => DataValue::Int(*i),
+                        StaticValue::Str(string) => DataValue::Str(bytecode.strings[*string].clone(), String::new()),
+                    }).collect();
+                    vm.machine.allocate_data(*width, &values)
+                }
+            };
+            let address = allocation.map_err(|message| vm.error(&message))?;
+            vm.statics.push(address);
+        }
+        Ok(vm)
+    }
+
+    fn run(&mut self) -> Result<(), Stop> {
+        let code = &self.bytecode.code;
+        loop {
+            let pc = self.pc;
+            self.pc += 1;
+            match &code[pc] {
+                Op::Halt => return Ok(()),
+                Op::Call(target) => {
+                    let limit = self.machine.options.call_limit();
+                    if self.frames.len() + 1 >= limit {
+                        return Err(self.error(&format!("return stack overflow, procedure calls are nested more than {} deep", limit)));
+                    }
+                    self.frames.push(Frame { return_pc: self.pc, bindings: self.bindings.len(), loops: self.loops.len() });
+                    self.pc = *target;
+                }
+                Op::TailCall(target) => {
+                    self.unwind_frame();
+                    self.pc = *target;
+                }
+                Op::Return => {
+                    self.unwind_frame();
+                    let Some(frame) = self.frames.pop() else {
+                        return Err(self.error("'main' returned, there is no procedure to return to, end the program with exit instead"));
+                    };
+                    self.pc = frame.return_pc;
+                }
+                Op::PushStr(string) => {
+                    let address = match self.strings[*string] {
+                        Some(address) => address,
+                        None => {
+                            let address = self.machine.string(&self.bytecode.strings[*string]).map_err(|message| self.error(&message))?;
+                            self.strings[*string] = Some(address);
+                            address
+                        }
+                    };
+                    self.push(self.bytecode.strings[*string].len() as i64)?;
+                    self.push(address)?;
+                }
+                Op::PushStatic(id) => self.push(self.statics[*id])?,
+                Op::Jump(target) => self.pc = *target,
+                Op::JumpIfZero(target) => {
+                    if self.pop()? == 0 {
+                        self.pc = *target;
+                    }
+                }
+                Op::Bind(count) => {
+                    // The first binding takes the deepest value
+                    let start = self.bindings.len();
+                    for _ in 0..*count {
+                        let value = self.pop()?;
+                        self.bindings.insert(start, value);
+                    }
+                }
+                Op::Unbind(count) => self.bindings.truncate(self.bindings.len().saturating_sub(*count)),
+                Op::Binding(depth) => {
+                    let Some(&value) = self.bindings.iter().rev().nth(*depth) else {
+                        return Err(self.error("invalid bytecode, the binding does not exist"));
+                    };
+                    self.push(value)?;
+                }
+                Op::For => {
+                    let end = self.pop()?;
+                    let start = self.pop()?;
+                    self.loops.push((start, end));
+                }
+                Op::ForCheck(target) => {
+                    let Some(&(index, end)) = self.loops.last() else {
+                        return Err(self.error("invalid bytecode, not inside of a for loop"));
+                    };
+                    if index >= end {
+                        self.pc = *target;
+                    }
+                }
+                Op::ForStep(target) => {
+                    let Some((index, _)) = self.loops.last_mut() else {
+                        return Err(self.error("invalid bytecode, not inside of a for loop"));
+                    };
+                    *index += 1;
+                    self.pc = *target;
+                }
+                Op::ForEnd => _ = self.loops.pop(),
+                Op::Index(depth) => {
+                    let Some(&(index, _)) = self.loops.iter().rev().nth(*depth) else {
+                        return Err(self.error("invalid bytecode, not inside of a for loop"));
+                    };
+                    self.push(index)?;
+                }
+                Op::Assert => {
+                    if self.pop()? == 0 {
+                        return Err(self.error("assertion failed"));
+                    }
+                }
+                Op::Panic(message) => return Err(self.error(&self.bytecode.strings[*message])),
+                primitive => self.machine.step(primitive).map_err(|trap| trap.stop(|message| self.error(message)))?,
+            }
+        }
+    }
+
+    /// Drops the bindings and loops of the procedure that is returning
+    fn unwind_frame(&mut self) {
+        let (bindings, loops) = self.frames.last().map_or((0, 0), |frame| (frame.bindings, frame.loops));
+        self.bindings.truncate(bindings);
+        self.loops.truncate(loops);
+    }
+
+    fn pop(&mut self) -> Result<i64, Stop> {
+        self.machine.pop().map_err(|message| self.error(&message))
+    }
+
+    fn push(&mut self, value: i64) -> Result<(), Stop> {
+        self.machine.push(value).map_err(|message| self.error(&message))
+    }
+
+    /// Reports an error at the instruction being executed
+    fn error(&mut self, message: &str) -> Stop {
+        let bytecode = self.bytecode;
+        let span = bytecode.location(self.pc.saturating_sub(1));
+
+        // The calls being executed are found through the instructions they return to
+        let pcs = std::iter::once(self.pc.saturating_sub(1)).chain(self.frames.iter().rev().map(|frame| frame.return_pc - 1));
+        let procedures: Vec<(&str, Span)> = pcs
+            .filter_map(|pc| bytecode.procedure(pc))
+            .map(|procedure| (bytecode.strings[procedure.identifier].as_str(), bytecode.span(&procedure.location)))
+            .collect();
+        let backtrace: Vec<(&str, &Span)> = procedures.iter().map(|(identifier, span)| (*identifier, span)).collect();
+        self.machine.report(&span, message, &backtrace)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::CompilerOptions;
+    use crate::lexer::Lexer;
+    use crate::parser::Parser;
+    use crate::{bytecode, checker, interpreter, preprocessor};
+
+    /// Runs the source like `stapel run` and like `stapel build --bytecode` followed by `stapel exec`,
+    /// asserts both end the same way and returns the exit code and the values left on the stack
+    fn run_both(source: &str) -> (i32, Vec<i64>) {
+        crate::recover_from_errors();
+        let options = CompilerOptions::default();
+        let mut l = Lexer::new(source.to_string(), String::from("test.spl"));
+        l.tokenize();
+        let mut p = Parser::new(preprocessor::process(l.tokens, &preprocessor::defines(&options)));
+        p.parse();
+        checker::check(&p.program, &options);
+        interpreter::resolve(&p.program, None);
+
+        let arguments = [String::from("test")];
+        let mut machine = Machine::new(options.clone(), &arguments);
+        let interpreted = (interpreter::run(&p.program, &mut machine), machine.stack);
+
+        let program = Bytecode::from_bytes(&bytecode::lower(&p.program, &options).to_bytes()).unwrap();
+        let mut machine = Machine::new(program.options(), &arguments);
+        let executed = (exec(&program, &mut machine), machine.stack);
+        assert_eq!(executed, interpreted);
+        executed
+    }
+
+    #[test]
+    fn returns_and_breaks_unwind_loops_and_bindings() {
+        let source = "
+            proc inner do
+                for 0 10 do
+                    i let x in
+                        if x 2 = do x return end
+                    end
+                end
+            end
+
+            proc main do
+                for 100 102 do
+                    i 5 let a b in
+                        inner i a b
+                        for 0 5 do if i 1 = do break end i end
+                        a
+                    end
+                end
+            end";
+        assert_eq!(run_both(source), (0, vec![2, 100, 100, 5, 0, 100, 2, 101, 101, 5, 0, 101]));
+    }
+
+    #[test]
+    fn tail_calls_do_not_nest() {
+        let source = "
+            proc count do
+                if dup 0 = do return end
+                1 - count return
+            end
+
+            proc main do 5000 count end";
+        assert_eq!(run_both(source), (0, vec![0]));
+    }
+
+    #[test]
+    fn break_and_continue_in_nested_loops() {
+        let source = "
+            proc main do
+                0
+                for 0 4 do
+                    if i 1 = do continue end
+                    0 while dup 5 < do
+                        1 +
+                        if dup 2 = do continue end
+                        if dup 4 = do break end
+                        swap over + swap
+                    end pop
+                    if i 3 = do break end
+                    i +
+                end
+                for 0 3 do
+                    for 10 13 do
+                        if i 11 = do continue end
+                        if i 12 = do break end
+                        i
+                    end
+                    i
+                end
+            end";
+        assert_eq!(run_both(source), (0, vec![14, 10, 0, 10, 1, 10, 2]));
+    }
+
+    /// Cases with consecutive values, which compiled programs look up in a jump table
+    #[test]
+    fn dense_match() {
+        let source = "
+            proc name do
+                match
+                    case 0 do 10
+                    case 1 do 11
+                    case 2 do 12
+                    case 3 do 13
+                    case 4 do 14
+                    case 5 do 15
+                    case 6 do 16
+                    case 7 do 17
+                    else 99
+                end
+            end
+
+            proc main do for -1 9 do i name end end";
+        assert_eq!(run_both(source), (0, vec![99, 10, 11, 12, 13, 14, 15, 16, 17, 99]));
+    }
+}